@@ -0,0 +1,83 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Index, Member};
+
+/// Derive `Bundle` for a struct, adding (or registering) each field as a component.
+/// Fields that are bundles themselves must be marked with `#[bundle]`, so that their own fields are added instead:
+/// ```ignore
+/// #[derive(Bundle)]
+/// pub struct PlayerBundle {
+///     pub player: Player,
+///     pub transform: Transform,
+///     #[bundle]
+///     pub sprite: SpriteBundle,
+/// }
+/// ```
+/// The generated code refers to `crate::bundle::Bundle`, so can only be used inside the game crate.
+#[proc_macro_derive(Bundle, attributes(bundle))]
+pub fn derive_bundle(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let Data::Struct(data) = &input.data else {
+        return syn::Error::new_spanned(name, "Bundle can only be derived for structs")
+            .to_compile_error()
+            .into()
+    };
+
+    let mut add_components: Vec<TokenStream2> = Vec::new();
+    let mut register_components: Vec<TokenStream2> = Vec::new();
+    let mut component_type_ids: Vec<TokenStream2> = Vec::new();
+    for (index, field) in data.fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(index)),
+        };
+        let ty = &field.ty;
+        let is_bundle = field.attrs
+            .iter()
+            .any(| attr | attr.path().is_ident("bundle"));
+        if is_bundle {
+            add_components.push(quote! {
+                crate::bundle::Bundle::add_components(self.#member, world, entity)?;
+            });
+            register_components.push(quote! {
+                <#ty as crate::bundle::Bundle>::register_components(world);
+            });
+            component_type_ids.push(quote! {
+                <#ty as crate::bundle::Bundle>::component_type_ids(type_ids);
+            });
+        } else {
+            add_components.push(quote! {
+                world.add_component(entity, self.#member)?;
+            });
+            register_components.push(quote! {
+                if !world.is_component_registered::<#ty>() {
+                    world.register_component::<#ty>();
+                }
+            });
+            component_type_ids.push(quote! {
+                type_ids.push(std::any::TypeId::of::<#ty>());
+            });
+        }
+    }
+
+    quote! {
+        impl #impl_generics crate::bundle::Bundle for #name #ty_generics #where_clause {
+            fn add_components(self, world: &mut crate::ecs::World, entity: &crate::ecs::Entity) -> Result<(), crate::ecs::EntityComponentError> {
+                #(#add_components)*
+                Ok(())
+            }
+
+            fn register_components(world: &mut crate::ecs::World) {
+                #(#register_components)*
+            }
+
+            fn component_type_ids(type_ids: &mut Vec<std::any::TypeId>) {
+                #(#component_type_ids)*
+            }
+        }
+    }.into()
+}
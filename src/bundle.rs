@@ -1,10 +1,61 @@
-use crate::{component::{self, Bullet, Collider, Sprite, Transform, Velocity}, ecs::{Entity, World}, linalg::f32};
+use std::any::TypeId;
 
-// TODO: It would be great to have a macro automatically derive `add_components`!
+use crate::{component::{self, Bullet, Collider, Sprite, Transform, Velocity}, ecs::{Component, Entity, EntityComponentError, World}, linalg::f32};
+
+pub use minigame_derive::Bundle;
+
+/// A group of components that are added to an Entity together. Usually derived, see `minigame_derive::Bundle`,
+/// but also implemented for tuples of components, e.g., `(Transform, Sprite)`.
 pub trait Bundle {
-    fn add_components(self, world: &mut World, entity: &Entity);
+    /// Add every component in the bundle to an Entity, stopping at the first that fails.
+    fn add_components(self, world: &mut World, entity: &Entity) -> Result<(), EntityComponentError>;
+    /// Register every component type in the bundle that isn't already registered.
+    fn register_components(world: &mut World);
+    /// Push the TypeId of every component type in the bundle. Used to reserve storage, see World::spawn_batch.
+    fn component_type_ids(type_ids: &mut Vec<TypeId>);
+}
+
+/// Implements Bundle for a tuple of components.
+macro_rules! impl_bundle_for_tuple {
+    ($($component:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($component: Component),*> Bundle for ($($component,)*) {
+            fn add_components(self, _world: &mut World, _entity: &Entity) -> Result<(), EntityComponentError> {
+                let ($($component,)*) = self;
+                $(_world.add_component(_entity, $component)?;)*
+                Ok(())
+            }
+
+            fn register_components(_world: &mut World) {
+                $(
+                    if !_world.is_component_registered::<$component>() {
+                        _world.register_component::<$component>();
+                    }
+                )*
+            }
+
+            fn component_type_ids(_type_ids: &mut Vec<TypeId>) {
+                $(_type_ids.push(TypeId::of::<$component>());)*
+            }
+        }
+    };
 }
 
+/// Implements Bundle for tuples of every length from the given list of type parameters down to zero.
+macro_rules! impl_bundles_for_tuples {
+    () => {
+        impl_bundle_for_tuple!();
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        impl_bundle_for_tuple!($head $(, $tail)*);
+        impl_bundles_for_tuples!($($tail),*);
+    };
+}
+
+// Tuple bundles can have up to 12 components
+impl_bundles_for_tuples!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+#[derive(Bundle)]
 pub struct BulletBundle {
     pub bullet: component::Bullet,
     pub transform: component::Transform,
@@ -13,16 +64,6 @@ pub struct BulletBundle {
     pub collider: component::Collider,
 }
 
-impl Bundle for BulletBundle {
-    fn add_components(self, world: &mut World, entity: &Entity) {
-        world.add_component(entity, self.bullet).unwrap();
-        world.add_component(entity, self.transform).unwrap();
-        world.add_component(entity, self.velocity).unwrap();
-        world.add_component(entity, self.sprite).unwrap();
-        world.add_component(entity, self.collider).unwrap();
-    }
-}
-
 impl Default for BulletBundle {
     fn default() -> Self {
         BulletBundle { 
@@ -1,7 +1,10 @@
 use std::collections::HashSet;
 
-use crate::{ecs::{Entity, World}, linalg::{f32::{self, Vec2}, u32, u8}};
+use miniquad::{Bindings, KeyCode, Pipeline, RenderingBackend};
 
+use crate::{ecs::{Commands, Entity, World}, linalg::{f32::{self, Vec2}, u32, u8}};
+
+/// Stored in the World as a resource, rather than on an Entity.
 pub struct TextureAtlas {
     pub uv_offsets: Vec<f32::Vec2>,
 }
@@ -37,12 +40,16 @@ impl TextureAtlas {
 pub struct TileMap {
     pub tiles: u8::Matrix,
     pub tile_positions: Vec<f32::Vec2>,
+    pub tile_size: f32::Vec2,
+    /// Tiles with these IDs get a wall collider. See `spawn_colliders`.
+    pub collidable_tile_ids: HashSet<u8>,
 }
 
 impl TileMap {
     pub fn new(
         tiles: u8::Matrix,
         tile_size: f32::Vec2,
+        collidable_tile_ids: HashSet<u8>,
     ) -> Self{
         let mut tile_positions = Vec::new();
         for (row_idx, row) in tiles.iter_rows().enumerate() {
@@ -56,28 +63,52 @@ impl TileMap {
         TileMap {
             tiles,
             tile_positions,
+            tile_size,
+            collidable_tile_ids,
         }
     }
 
-    // FIXME: Think this should be an initialisation system instead
+    /// Spawn a wall collider (as a child of the tile map) for every collidable tile.
+    /// Registered as an `on_add` hook, so that every TileMap gets its colliders when it is added to an Entity:
+    /// ```
+    /// world.on_add::<TileMap>(TileMap::spawn_colliders);
+    /// ```
     pub fn spawn_colliders(
-        &self,
-        world: &mut World,
-        self_entity: &Entity,
-        tile_size: f32::Vec2,
-        collidable_tile_ids: &HashSet<u8>,
+        self_entity: Entity,
+        commands: &mut Commands,
     ) {
-        for (idx, tile_value) in self.tiles.iter().enumerate() {
-            if collidable_tile_ids.contains(tile_value) {
-                let position = self.tile_positions[idx];
-
-                let collider = world.create_entity();
-                world.add_component(&collider, ChildOf { parent: *self_entity }).unwrap();
-                world.add_component(&collider, Transform { position }).unwrap();
-                world.add_component(&collider, Collider { size: tile_size, is_static: true }).unwrap();
-                world.add_component(&collider, Wall { }).unwrap();
+        commands.add(move | world: &mut World | {
+            // The TileMap may have been removed by an earlier command
+            let Ok(Some(tile_map)) = world.get_component::<TileMap>(&self_entity) else {
+                return
+            };
+            let tile_size = tile_map.tile_size;
+            let colliders: Vec<(Transform, Collider, Wall)> = tile_map.tiles
+                .iter()
+                .enumerate()
+                .filter(| (_, tile_value) | tile_map.collidable_tile_ids.contains(tile_value))
+                .map(| (idx, _) | (
+                    Transform { position: tile_map.tile_positions[idx] },
+                    Collider { size: tile_size, is_static: true },
+                    Wall { },
+                ))
+                .collect();
+            drop(tile_map);
+
+            // Won't panic if the colliders can't be spawned (e.g., the allocator is exhausted), will just log
+            let colliders = match world.spawn_batch(colliders) {
+                Ok(colliders) => colliders,
+                Err(error) => {
+                    println!("Failed to spawn the tile map's colliders: {:?}", error);
+                    return
+                },
+            };
+            for collider in colliders {
+                if let Err(error) = world.set_parent(collider, self_entity) {
+                    println!("Failed to parent a collider to the tile map: {:?}", error);
+                }
             }
-        }
+        });
     }
 }
 
@@ -89,19 +120,34 @@ pub struct Bullet { }
 
 pub struct Wall { }
 
+/// The position of an Entity relative to its parent (see World::set_parent), or to the world if it has none.
 pub struct Transform {
     pub position: f32::Vec2,
 }
 
-pub struct Velocity {
-    pub vec: f32::Vec2,
+/// The position of an Entity in the world: its Transform combined with the Transforms of all of its ancestors.
+/// Kept up to date by `transform_propagation_system`, so should be read rather than written.
+pub struct GlobalTransform {
+    pub position: f32::Vec2,
 }
 
-// This is kind of a cop out from implementing proper hierarchical components (`Parent` and `Child`).
-// There aren't currently any objects in the game that require more than a single layer hierarchy,
-// so this will suffice (for now?)
-pub struct ChildOf {
-    pub parent: Entity,
+impl GlobalTransform {
+    /// Give an Entity a GlobalTransform. Registered as an `on_add` hook for Transform,
+    /// so that every Entity with a Transform has one:
+    /// ```
+    /// world.on_add::<Transform>(GlobalTransform::add_to);
+    /// ```
+    pub fn add_to(
+        entity: Entity,
+        commands: &mut Commands,
+    ) {
+        // Placeholder until the next time transforms are propagated
+        commands.insert(entity, GlobalTransform { position: f32::Vec2 { x: 0.0, y: 0.0 } });
+    }
+}
+
+pub struct Velocity {
+    pub vec: f32::Vec2,
 }
 
 pub struct Sprite {
@@ -110,6 +156,7 @@ pub struct Sprite {
 
 pub struct ShootsBullet {
     pub bullet_speed: f32,
+    #[allow(dead_code)]
     pub is_active: bool,
 }
 
@@ -118,7 +165,32 @@ pub struct Collider {
     pub is_static: bool,
 }
 
+/// Sent as an event (see Events) when `entity_a`'s collider overlaps `entity_b`'s.
 pub struct CollisionEvent { 
     pub entity_a: Entity,
     pub entity_b: Entity,
-}
\ No newline at end of file
+}
+
+/// Stored in the World as a resource. Updated by Stage's input event handlers.
+pub struct Input {
+    pub pressed_keys: HashSet<KeyCode>,
+    pub mouse_position: f32::Vec2,
+}
+
+/// Stored in the World as a resource. Updated at the start of each Stage::update.
+pub struct Time {
+    /// Seconds since the Unix epoch, at the start of the current update.
+    pub now: f64,
+    /// Seconds between the start of the previous update and the start of the current one.
+    pub delta: f64,
+}
+
+/// Stored in the World as a non-send resource, as the rendering context must stay on the main thread.
+/// Everything `render_system` needs to draw a frame.
+pub struct Renderer {
+    pub ctx: Box<dyn RenderingBackend>,
+    pub pipeline: Pipeline,
+    pub bindings: Bindings,
+    /// The number of instances currently in the instance buffers.
+    pub num_instances: usize,
+}
@@ -1,9 +1,68 @@
-use std::{any::{Any, TypeId}, cell::{Ref, RefCell, RefMut}, collections::HashMap};
+use std::{any::{Any, TypeId}, collections::HashMap, mem, ptr::NonNull, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Mutex}, thread::{self, ThreadId}};
 
 use crate::bundle::Bundle;
 
-type EntityId = u16;
-type EntityGeneration = u64; // TODO: This is probably overkill, but saves having to check if we've run out of generations. Make a choice later!
+mod archetype;
+mod cell;
+mod change_detection;
+mod commands;
+mod event;
+mod executor;
+mod hierarchy;
+mod hooks;
+mod query;
+mod schedule;
+mod sparse_array;
+mod system;
+mod thread_pool;
+
+use archetype::{Archetypes, ComponentColumns};
+use cell::BorrowCell;
+use hooks::{ComponentHooks, HookKind};
+use sparse_array::SparseArray;
+use thread_pool::ThreadPool;
+
+pub use archetype::{ArchetypeId, EntityLocation};
+pub use cell::{Ref, RefMut};
+pub use change_detection::{ComponentTicks, SystemTicks, Tick};
+pub use commands::{CommandQueue, Commands};
+pub use event::Events;
+pub use hierarchy::{Children, Parent};
+pub use hooks::ComponentHook;
+pub use query::{Changed, Query, QueryIter, With, Without};
+pub use schedule::Schedule;
+pub use sparse_array::StorageHint;
+pub use system::{EventReader, EventWriter, IntoSystem, NonSendMut, QueryParam, RemovedComponents, Res, ResMut, System, SystemParam};
+// Appear in the signatures above, or complete the API, but the game doesn't name them (yet)
+#[allow(unused_imports)]
+pub use {
+    archetype::Archetype,
+    event::EventCursor,
+    hierarchy::HierarchyError,
+    query::Added,
+    schedule::SystemConfig,
+    system::{FunctionSystem, NonSend, SystemAccess, SystemParamFunction},
+};
+
+/// Systems may run in parallel (see Schedule), so components must be safe to share between threads.
+/// Implemented for every type that is.
+pub trait Component: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Component for T {}
+
+/// Must be safe to share between threads, like Component.
+/// Resources that aren't can be inserted as non-send resources instead, see World::insert_non_send_resource.
+pub trait Resource: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Resource for T {}
+
+/// Must be safe to share between threads, like Component.
+pub trait Event: Send + Sync + 'static {}
+
+impl<T: Send + Sync + 'static> Event for T {}
+
+type EntityId = u32;
+type EntityGeneration = u32;
 
 #[derive(Debug)]
 pub enum EntityError {
@@ -11,6 +70,8 @@ pub enum EntityError {
     OutOfBounds,
     /// An Entity was invalid (either dead or its generation was outdated).
     InvalidEntity,
+    /// Every Entity ID is either in use or retired, so no more Entities can be created.
+    Exhausted,
 }
 
 #[derive(Debug)]
@@ -19,21 +80,45 @@ pub enum EntityComponentError {
     InvalidEntity,
     /// A component was expected to be registered, but it was not.
     UnregisteredComponent,
+    /// No Entity could be created to add components to. See EntityError::Exhausted.
+    Exhausted,
+    /// Parent and Children can't be removed directly, as the other side of the hierarchy would be left pointing at the Entity.
+    /// Use World::remove_parent instead.
+    HierarchyComponent,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+/// A handle to an Entity: its ID in the low 32 bits, and its generation in the high 32 bits.
+/// IDs are recycled once an Entity is destroyed, so the generation tells apart Entities that shared an ID.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Entity {
-    id: EntityId,
-    generation: EntityGeneration,
+    bits: u64,
 }
 
 impl Entity {
+    fn new(
+        id: EntityId,
+        generation: EntityGeneration,
+    ) -> Entity {
+        Entity {
+            bits: (generation as u64) << 32 | id as u64,
+        }
+    }
+
     pub fn get_id(&self) -> EntityId {
-        self.id
+        self.bits as EntityId
     }
 
     pub fn get_generation(&self) -> EntityGeneration {
-        self.generation
+        (self.bits >> 32) as EntityGeneration
+    }
+}
+
+impl std::fmt::Debug for Entity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Entity")
+            .field("id", &self.get_id())
+            .field("generation", &self.get_generation())
+            .finish()
     }
 }
 
@@ -54,7 +139,12 @@ struct EntityAllocator {
     /// If an Entity was allocated and then deallocated, it will be added to this Vec
     /// until it gets allocated again. This, along with our generational indexing,
     /// helps to keep our `entries` Vec small!
+    /// IDs whose generation has reached EntityGeneration::MAX are retired instead (never added),
+    /// so that a stale handle can never become valid again by its generation wrapping around.
     available_entity_ids: Vec<EntityId>,
+    /// The number of IDs that have been reserved, but not yet allocated.
+    /// IDs are reserved from the end of `available_entity_ids` first, then past the end of `entries`.
+    num_reserved: AtomicUsize,
 }
 
 impl EntityAllocator {
@@ -62,45 +152,100 @@ impl EntityAllocator {
         EntityAllocator {
             entries: Vec::new(), // TODO: Could be good to allocate with some initial capacity
             available_entity_ids: Vec::new(),
+            num_reserved: AtomicUsize::new(0),
         }
     }
 
-    pub fn allocate(&mut self) -> Entity {
+    /// Reserve an Entity without allocating it, so it can be referred to before it exists.
+    /// Reserved Entities are invalid until they are allocated by `flush`.
+    /// Reservations are atomic, so Entities can be reserved from several threads at once.
+    pub fn reserve(&self) -> Result<Entity, EntityError> {
+        let num_reserved = self.num_reserved.fetch_add(1, Ordering::Relaxed);
+        let num_available = self.available_entity_ids.len();
+        if num_reserved < num_available {
+            // Reserve recyclable IDs in the same order `allocate` would pop them
+            let reusable_entity_id = self.available_entity_ids[num_available - 1 - num_reserved];
+            let reusable_entry = &self.entries[reusable_entity_id as usize];
+            return Ok(Entity::new(reusable_entity_id, reusable_entry.generation + 1))
+        }
+        let new_entity_id = self.entries.len() + num_reserved - num_available;
+        if new_entity_id > EntityId::MAX as usize {
+            // Every later reservation fails too, so undoing this one leaves exactly the successful ones reserved
+            self.num_reserved.fetch_sub(1, Ordering::Relaxed);
+            return Err(EntityError::Exhausted)
+        }
+        Ok(Entity::new(new_entity_id as EntityId, 0))
+    }
+
+    /// Allocate every reserved Entity, returning them in the order they were reserved.
+    pub fn flush(&mut self) -> Vec<Entity> {
+        let num_reserved = std::mem::take(self.num_reserved.get_mut());
+        (0..num_reserved)
+            .map(| _ | self.allocate().expect("Reserved an Entity that could not be allocated!"))
+            .collect()
+    }
+
+    pub fn allocate(&mut self) -> Result<Entity, EntityError> {
         match self.available_entity_ids.pop() {
             Some(reusable_entity_id) => {
                 let reusable_entry = &mut self.entries[reusable_entity_id as usize];
                 reusable_entry.is_alive = true; // Mark this Entity as alive again
                 reusable_entry.generation += 1; // Increment this Entity's generation to make it distinct
-                Entity { id: reusable_entity_id as EntityId, generation: reusable_entry.generation }
+                Ok(Entity::new(reusable_entity_id, reusable_entry.generation))
             }
             None => {
+                if self.entries.len() > EntityId::MAX as usize {
+                    return Err(EntityError::Exhausted)
+                }
                 self.entries.push(EntityAllocatorEntry { is_alive: true, generation: 0 });
-                // FIXME: No protection for overflows here! If `self.entries.len()` is > EntityId::MAX,
-                // things will probably go very wrong!
-                Entity { id: self.entries.len() as EntityId - 1, generation: 0 }
+                Ok(Entity::new((self.entries.len() - 1) as EntityId, 0))
             }
         }
     }
 
+    /// Allocate `num_entities` Entities at once, growing `entries` only once.
+    /// Fails (without allocating any) if there aren't enough Entity IDs left for all of them.
+    pub fn allocate_batch(
+        &mut self,
+        num_entities: usize,
+    ) -> Result<Vec<Entity>, EntityError> {
+        let num_new = num_entities.saturating_sub(self.available_entity_ids.len());
+        if self.entries.len() + num_new > EntityId::MAX as usize + 1 {
+            return Err(EntityError::Exhausted)
+        }
+        self.entries.reserve(num_new);
+        (0..num_entities)
+            .map(| _ | self.allocate())
+            .collect()
+    }
+
     pub fn deallocate(
         &mut self,
         entity: Entity,
     ) -> Result<(), EntityError> {
-        match self.entries.get_mut(entity.id as usize) {
+        match self.entries.get_mut(entity.get_id() as usize) {
+            // Deallocating a dead or outdated Entity would free its ID a second time
+            Some(deallocated_entry) if !deallocated_entry.is_alive || deallocated_entry.generation != entity.get_generation() => {
+                Err(EntityError::InvalidEntity)
+            }
             Some(deallocated_entry) => {
                 deallocated_entry.is_alive = false; // Mark this Entity as dead
-                self.available_entity_ids.push(entity.id); // Mark this Entity id as reusable
+                // Retire this Entity id once it runs out of generations, otherwise mark it as reusable
+                if deallocated_entry.generation < EntityGeneration::MAX {
+                    self.available_entity_ids.push(entity.get_id());
+                }
                 Ok(())
             }
             None => Err(EntityError::OutOfBounds)
         }
     }
     
+    #[allow(dead_code)]
     pub fn is_alive(
         &self,
         entity: &Entity,
     ) -> bool {
-        match self.entries.get(entity.id as usize) {
+        match self.entries.get(entity.get_id() as usize) {
             Some(entry) => entry.is_alive,
             None => false,
         }
@@ -110,30 +255,27 @@ impl EntityAllocator {
         &self,
         entity: &Entity,
     ) -> bool {
-        match self.entries.get(entity.id as usize) {
+        match self.entries.get(entity.get_id() as usize) {
             Some(entry) => {
                 // To be considered valid, the entity must be alive and of the current generation
-                entry.is_alive && entry.generation == entity.generation
+                entry.is_alive && entry.generation == entity.get_generation()
             }
             None => {
                 false
             }
         }
     }
-    
-    pub fn get_num_entries(&self) -> usize {
-        self.entries.len()
-    }
+
 }
 
 /// Used to map from a sparsely packed collection of Entities to
 /// a densely packed collection of Components
 struct ComponentPool<T> {
-    /// The length of this Vec equals the number of entries in the EntityAllocator.
-    /// If an Entity has a Component of type <T>, its index in this Vec will contain
-    /// an index to entities_with_component. Othersise, its index in this Vec will contain None.
-    /// Use this Vec e.g. to check if a given Entity has a Component of type <T>.
-    pub all_entities: Vec<Option<usize>>,
+    /// If an Entity has a Component of type <T>, its ID maps to an index to entities_with_component.
+    /// Otherwise, its ID maps to None.
+    /// Only the pages covering Entities with a Component of type <T> are allocated (see StorageHint).
+    /// Use this e.g. to check if a given Entity has a Component of type <T>.
+    sparse: SparseArray,
     /// The length of this Vec equals the number of Entities with Component of type <T>.
     /// Each entry contains an Entity.
     /// This Vec is parallel with the components Vec.
@@ -143,17 +285,94 @@ struct ComponentPool<T> {
     /// Each entry contains a Component of type <T>.
     /// This Vec is parallel with entities_with_component.
     components: Vec<T>,
+    /// When each component was added and last changed.
+    /// This Vec is parallel with components.
+    ticks: Vec<ComponentTicks>,
 }
 
-/// Enables registering Entities in type-erased ComponentPools.
-trait ComponentStorage: Any {
+/// How the components of a given type are stored in the World.
+/// Chosen per component type when it is registered; see World::register_component_with_storage.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StorageType {
+    /// One ComponentPool (sparse set) for the component type.
+    /// Adding and removing the component is cheap, but iterating over it alongside
+    /// other components needs a lookup per component per Entity.
+    SparseSet,
+    /// Entities with the same set of Table components are stored together, in contiguous columns.
+    /// Iterating over several Table components at once is linear, but adding or removing one
+    /// moves all of the Entity's Table components into a different archetype.
+    Table,
+}
+
+impl<T> ComponentPool<T> {
+    fn new(hint: StorageHint) -> ComponentPool<T> {
+        ComponentPool {
+            sparse: SparseArray::new(hint),
+            entities_with_component: Vec::new(),
+            components: Vec::new(),
+            ticks: Vec::new(),
+        }
+    }
+
+    /// Get the index of an Entity's component in `components`, if it has one.
+    fn get_dense_index(
+        &self,
+        entity: &Entity,
+    ) -> Option<usize> {
+        self.sparse.get(entity.get_id() as usize)
+    }
+
+    /// Copy out an Entity's component ticks, if it has the component, without creating a reference into `ticks`.
+    /// Used while the components' RefMuts may be writing to other ticks in the pool (see TicksFetch).
+    fn read_ticks(
+        &self,
+        entity: &Entity,
+    ) -> Option<ComponentTicks> {
+        let dense_data_index = self.get_dense_index(entity)?;
+        // SAFETY: every index in `sparse` is in bounds of `ticks`
+        Some(unsafe { self.ticks.as_ptr().add(dense_data_index).read() })
+    }
+
+    /// Remove an Entity's component from the pool, returning it (if the Entity had one).
+    fn swap_remove(
+        &mut self,
+        entity: &Entity,
+    ) -> Option<T> {
+        let entities_with_component_index = self.sparse.remove(entity.get_id() as usize)?;
+        // Swap the last component into the removed component's place
+        // (minimises number of changes that need to be made to `sparse`)
+        self.entities_with_component.swap_remove(entities_with_component_index);
+        self.ticks.swap_remove(entities_with_component_index);
+        let component = self.components.swap_remove(entities_with_component_index);
+        // Modify entry in `sparse` for swapped component
+        if let Some(swapped_entity) = self.entities_with_component.get(entities_with_component_index) {
+            self.sparse.insert(swapped_entity.get_id() as usize, entities_with_component_index);
+        }
+        Some(component)
+    }
+}
+
+/// Enables removing Entities in type-erased ComponentPools and ComponentColumns.
+trait ComponentStorage: Any + Send + Sync {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
-    fn register_entity(&mut self, entity: &Entity);
-    fn get_entities_with_component(&self) -> &Vec<Entity>;
+    /// Remove (and drop) an Entity's component, returning whether it had one.
+    /// Only meaningful for SparseSet storage.
+    fn remove_entity(&mut self, entity: &Entity) -> bool;
+    /// Make room for `additional` more components without reallocating.
+    /// For Table storage, the room is made in the column of the archetype with ID `archetype_id`.
+    fn reserve(&mut self, archetype_id: ArchetypeId, additional: usize);
+    /// Move the component in a row of one archetype's column to the end of another archetype's column.
+    /// Only meaningful for Table storage.
+    fn move_row(&mut self, location: EntityLocation, new_archetype_id: ArchetypeId);
+    /// Remove (and drop) the component in a row of an archetype's column.
+    /// Only meaningful for Table storage.
+    fn remove_row(&mut self, location: EntityLocation);
+    /// Does no Entity have a component in this storage?
+    fn is_empty(&self) -> bool;
 }
 
-impl<T: 'static> ComponentStorage for ComponentPool<T> {
+impl<T: Component> ComponentStorage for ComponentPool<T> {
     fn as_any(&self) -> &dyn Any {
         self
     }
@@ -162,23 +381,45 @@ impl<T: 'static> ComponentStorage for ComponentPool<T> {
         self
     }
 
-    fn register_entity(&mut self, entity: &Entity) {
-        let index = entity.id as usize;
-        if self.all_entities.len() <= index {
-            self.all_entities.resize(index + 1, None);
-        }
+    fn remove_entity(&mut self, entity: &Entity) -> bool {
+        self.swap_remove(entity).is_some()
+    }
+
+    fn reserve(&mut self, _archetype_id: ArchetypeId, additional: usize) {
+        self.entities_with_component.reserve(additional);
+        self.components.reserve(additional);
+        self.ticks.reserve(additional);
+    }
+
+    fn move_row(&mut self, _location: EntityLocation, _new_archetype_id: ArchetypeId) {
+        unreachable!("ComponentPools are not stored in archetypes!")
+    }
+
+    fn remove_row(&mut self, _location: EntityLocation) {
+        unreachable!("ComponentPools are not stored in archetypes!")
     }
 
-    fn get_entities_with_component(&self) -> &Vec<Entity> {
-        &self.entities_with_component
+    fn is_empty(&self) -> bool {
+        self.entities_with_component.is_empty()
     }
 }
 
-/// Maps from a Component type to its ComponentPool.
+/// A type-erased component storage, along with the StorageType it implements.
+struct ComponentEntry {
+    storage_type: StorageType,
+    storage: BorrowCell<Box<dyn ComponentStorage>>,
+    /// Entities that have lost a component of this type, either by removal or by being destroyed.
+    /// See World::get_removed_components.
+    removed: BorrowCell<Events<Entity>>,
+    /// Callbacks run when a component of this type is added, replaced or removed. See World::on_add.
+    hooks: ComponentHooks,
+}
+
+/// Maps from a Component type to its storage (a ComponentPool or ComponentColumns).
 /// Supports `ComponentStorage` behaviours (e.g., registering a new Entity)
-/// without downcasting to a ComponentPool with a concrete <T>.
+/// without downcasting to a storage with a concrete <T>.
 struct ComponentMap {
-    map: HashMap<TypeId, RefCell<Box<dyn ComponentStorage>>>,
+    map: HashMap<TypeId, ComponentEntry>,
 }
 
 impl ComponentMap {
@@ -188,69 +429,232 @@ impl ComponentMap {
         }
     }
 
-    fn insert<T>(
+    /// Insert the storage for components of type T.
+    /// If T is already registered, its hooks and removal log are kept, and:
+    /// - if no Entity has a T yet, its storage is replaced with `storage`,
+    /// - if Entities do have a T and `storage_type` matches, nothing changes,
+    /// - otherwise, this panics, as the existing components can't be moved to the new storage type.
+    fn insert<T, S>(
         &mut self,
-        value: T,
-    ) 
+        storage_type: StorageType,
+        storage: S,
+    )
     where
-        T: ComponentStorage + Any + 'static
+        T: 'static,
+        S: ComponentStorage + Any + 'static
     {
-        self.map.insert(TypeId::of::<T>(), RefCell::new(Box::new(value)));
+        match self.map.get_mut(&TypeId::of::<T>()) {
+            Some(component_entry) if component_entry.storage.borrow().is_empty() => {
+                component_entry.storage_type = storage_type;
+                *component_entry.storage.get_mut() = Box::new(storage);
+            },
+            Some(component_entry) => assert!(
+                component_entry.storage_type == storage_type,
+                "Tried to register {} components as {:?}, but Entities already have them stored as {:?}!",
+                std::any::type_name::<T>(), storage_type, component_entry.storage_type,
+            ),
+            None => {
+                self.map.insert(TypeId::of::<T>(), ComponentEntry {
+                    storage_type,
+                    storage: BorrowCell::new(Box::new(storage)),
+                    removed: BorrowCell::new(Events::new()),
+                    hooks: ComponentHooks::default(),
+                });
+            },
+        }
     }
 
-    fn get<T>(&self) -> Option<Ref<Box<dyn ComponentStorage>>>
-    where
-        T: ComponentStorage + Any + 'static
-    {
+    fn get_storage_type(
+        &self,
+        type_id: &TypeId,
+    ) -> Option<StorageType> {
         self.map
-            .get(&TypeId::of::<T>())
-            .map(| cell | {
-                cell.borrow()
+            .get(type_id)
+            .map(| entry | entry.storage_type)
+    }
+
+    fn get(
+        &self,
+        type_id: &TypeId,
+    ) -> Option<Ref<'_, Box<dyn ComponentStorage>>> {
+        self.map
+            .get(type_id)
+            .map(| entry | {
+                entry.storage.borrow()
             })
     }
 
-    fn get_mut<T>(&self) -> Option<RefMut<Box<dyn ComponentStorage>>>
-    where
-        T: ComponentStorage + Any + 'static
-    {
+    fn get_mut(
+        &self,
+        type_id: &TypeId,
+    ) -> Option<RefMut<'_, Box<dyn ComponentStorage>>> {
         self.map
-            .get(&TypeId::of::<T>())
-            .map(| cell | {
-                cell.borrow_mut()
+            .get(type_id)
+            .map(| entry | {
+                entry.storage.borrow_mut()
             })
     }
 
-    /// Get a mutable object with type T (will downcast).
+    /// Get the storage for components of type T, as an object with type S (will downcast).
     /// To avoid downcasting, try:
     /// ```
-    /// self.get::&TypeId::of::<T>()
+    /// self.get(&TypeId::of::<T>())
     /// ```
-    fn get_typed<T>(&self) -> Option<Ref<T>>
+    fn get_typed<T, S>(&self) -> Option<Ref<'_, S>>
     where
-        T: ComponentStorage + Any + 'static
+        T: 'static,
+        S: ComponentStorage + Any + 'static
     {
-        Ref::filter_map(self.get::<T>()?, | boxed | {
+        Ref::filter_map(self.get(&TypeId::of::<T>())?, | boxed | {
             boxed
                 .as_any()
-                .downcast_ref::<T>()
+                .downcast_ref::<S>()
         }).ok()
     }
 
-    /// Get a mutable object with type T (will downcast).
+    /// Get the storage for components of type T, as a mutable object with type S (will downcast).
     /// To avoid downcasting, try:
     /// ```
-    /// self.get_mut::&TypeId::of::<T>()
+    /// self.get_mut(&TypeId::of::<T>())
     /// ```
-    fn get_typed_mut<T>(&self) -> Option<RefMut<T>>
+    fn get_typed_mut<T, S>(&self) -> Option<RefMut<'_, S>>
     where
-        T: ComponentStorage + Any + 'static
+        T: 'static,
+        S: ComponentStorage + Any + 'static
     {
-        RefMut::filter_map(self.get_mut::<T>()?, | boxed | {
+        RefMut::filter_map(self.get_mut(&TypeId::of::<T>())?, | boxed | {
             boxed
                 .as_any_mut()
-                .downcast_mut::<T>()
+                .downcast_mut::<S>()
+        }).ok()
+    }
+}
+
+/// Maps from a type to the single value of that type stored in the World (a "resource").
+struct ResourceMap {
+    map: HashMap<TypeId, BorrowCell<Box<dyn Any + Send + Sync>>>,
+}
+
+impl ResourceMap {
+    fn new() -> Self {
+        ResourceMap {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Insert the resource of type R, returning the previous one (if there was one).
+    fn insert<R: Resource>(
+        &mut self,
+        resource: R,
+    ) -> Option<R> {
+        self.map
+            .insert(TypeId::of::<R>(), BorrowCell::new(Box::new(resource)))
+            .and_then(Self::downcast)
+    }
+
+    fn get<R: Resource>(&self) -> Option<Ref<'_, R>> {
+        Ref::filter_map(self.map.get(&TypeId::of::<R>())?.borrow(), | boxed | {
+            boxed.downcast_ref::<R>()
+        }).ok()
+    }
+
+    fn get_mut<R: Resource>(&self) -> Option<RefMut<'_, R>> {
+        RefMut::filter_map(self.map.get(&TypeId::of::<R>())?.borrow_mut(), | boxed | {
+            boxed.downcast_mut::<R>()
+        }).ok()
+    }
+
+    fn remove<R: Resource>(&mut self) -> Option<R> {
+        self.map
+            .remove(&TypeId::of::<R>())
+            .and_then(Self::downcast)
+    }
+
+    /// Take a resource out of its BorrowCell, as an object with type R.
+    fn downcast<R: Resource>(resource: BorrowCell<Box<dyn Any + Send + Sync>>) -> Option<R> {
+        resource
+            .into_inner()
+            .downcast::<R>()
+            .ok()
+            .map(| resource | *resource)
+    }
+}
+
+/// Like ResourceMap, but for resources that can't be shared between threads (e.g., a rendering context).
+/// Non-send resources can only be accessed from the thread that created the World.
+struct NonSendResourceMap {
+    map: HashMap<TypeId, BorrowCell<Box<dyn Any>>>,
+    main_thread: ThreadId,
+}
+
+// SAFETY: every access to a non-send resource checks that it happens on the main thread,
+// and dropping the map on any other thread leaks the resources rather than dropping them
+unsafe impl Send for NonSendResourceMap {}
+unsafe impl Sync for NonSendResourceMap {}
+
+impl NonSendResourceMap {
+    fn new() -> Self {
+        NonSendResourceMap {
+            map: HashMap::new(),
+            main_thread: thread::current().id(),
+        }
+    }
+
+    fn check_thread(&self) {
+        assert!(thread::current().id() == self.main_thread, "Tried to access a non-send resource from a thread other than the main thread!");
+    }
+
+    /// Insert the non-send resource of type R, returning the previous one (if there was one).
+    fn insert<R: 'static>(
+        &mut self,
+        resource: R,
+    ) -> Option<R> {
+        self.check_thread();
+        self.map
+            .insert(TypeId::of::<R>(), BorrowCell::new(Box::new(resource)))
+            .and_then(Self::downcast)
+    }
+
+    fn get<R: 'static>(&self) -> Option<Ref<'_, R>> {
+        self.check_thread();
+        Ref::filter_map(self.map.get(&TypeId::of::<R>())?.borrow(), | boxed | {
+            boxed.downcast_ref::<R>()
+        }).ok()
+    }
+
+    fn get_mut<R: 'static>(&self) -> Option<RefMut<'_, R>> {
+        self.check_thread();
+        RefMut::filter_map(self.map.get(&TypeId::of::<R>())?.borrow_mut(), | boxed | {
+            boxed.downcast_mut::<R>()
         }).ok()
     }
+
+    fn remove<R: 'static>(&mut self) -> Option<R> {
+        self.check_thread();
+        self.map
+            .remove(&TypeId::of::<R>())
+            .and_then(Self::downcast)
+    }
+
+    /// Take a non-send resource out of its BorrowCell, as an object with type R.
+    fn downcast<R: 'static>(resource: BorrowCell<Box<dyn Any>>) -> Option<R> {
+        resource
+            .into_inner()
+            .downcast::<R>()
+            .ok()
+            .map(| resource | *resource)
+    }
+}
+
+impl Drop for NonSendResourceMap {
+    fn drop(&mut self) {
+        // The resources can't be dropped on any other thread, so leak them instead (rather than panicking,
+        // which would still drop them while unwinding)
+        if !self.map.is_empty() && thread::current().id() != self.main_thread {
+            println!("Leaking non-send resources, as the World was dropped on a thread other than the main thread!");
+            mem::forget(mem::take(&mut self.map));
+        }
+    }
 }
 
 pub struct World {
@@ -258,172 +662,372 @@ pub struct World {
     entity_allocator: EntityAllocator,
     /// Used for typed component access (e.g., for entity-component queries)
     component_pools: ComponentMap,
+    /// Used to find the Table components of each Entity
+    archetypes: BorrowCell<Archetypes>,
+    /// Used for typed access to singletons that don't belong to any Entity (e.g., input state)
+    resources: ResourceMap,
+    /// Used for typed access to singletons that must stay on the main thread (e.g., the rendering context)
+    non_send_resources: NonSendResourceMap,
+    /// Used to update the Events resource of every registered event type
+    event_updaters: Vec<fn(&World)>,
+    /// Used to record when components are added and changed. See World::increment_change_tick
+    change_tick: AtomicU64,
+    /// Used to record the changes made by component hooks, until they are applied. See World::on_add
+    hook_commands: Mutex<CommandQueue>,
+    /// Used to run systems (see Schedule::run) and query matches (see QueryIter::par_for_each) in parallel.
+    /// Locked by whichever is using the workers, so the two never use them at the same time
+    thread_pool: Mutex<ThreadPool>,
 }
 
 impl World {
     pub fn new() -> World {
-        World {
+        let mut world = World {
             entity_allocator: EntityAllocator::new(),
             component_pools: ComponentMap::new(),
+            archetypes: BorrowCell::new(Archetypes::new()),
+            resources: ResourceMap::new(),
+            non_send_resources: NonSendResourceMap::new(),
+            event_updaters: Vec::new(),
+            // Systems that have never run have a last run of 0, so start later than that
+            change_tick: AtomicU64::new(1),
+            hook_commands: Mutex::new(CommandQueue::new()),
+            thread_pool: Mutex::new(ThreadPool::new()),
+        };
+        // The hierarchy is built into every World, see World::set_parent
+        world.register_component::<Parent>();
+        world.register_component::<Children>();
+        world
+    }
+
+    /// Get the World's current tick. Changes made outside of systems are recorded at this tick.
+    pub fn get_change_tick(&self) -> Tick {
+        self.change_tick.load(Ordering::Acquire)
+    }
+
+    /// Advance the World's tick, returning the tick before it was advanced.
+    /// Called every time a system runs, to get the tick the system runs at (see SystemTicks).
+    /// The tick advances per system run, rather than per Schedule run, so that every change made after
+    /// a system ran (even later in the same Schedule run) is recorded at a later tick than the system's run.
+    pub fn increment_change_tick(&self) -> Tick {
+        self.change_tick.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Create a new Entity, and register it in the empty archetype.
+    /// Fails if every Entity ID is in use or retired.
+    pub fn create_entity(&mut self) -> Result<Entity, EntityError> {
+        self.flush_entities();
+        let entity = self.entity_allocator.allocate()?;
+        self.register_entity(&entity);
+        Ok(entity)
+    }
+
+    /// Reserve an Entity that will be created at the next sync point (see CommandQueue::apply).
+    /// The reserved Entity can be referred to straight away (e.g., by other commands),
+    /// but is invalid until it has been created.
+    /// Fails if every Entity ID is in use, retired or already reserved.
+    pub fn reserve_entity(&self) -> Result<Entity, EntityError> {
+        self.entity_allocator.reserve()
+    }
+
+    /// Create every reserved Entity.
+    pub fn flush_entities(&mut self) {
+        for entity in self.entity_allocator.flush() {
+            self.register_entity(&entity);
         }
     }
 
-    /// Create a new Entity, and register it with all ComponentPools
-    pub fn create_entity(&mut self) -> Entity {
-        let entity = self.entity_allocator.allocate();
-        self.component_pools.map
-            .iter_mut()
-            .for_each(| (_, component_storage) | {
-                component_storage.borrow_mut().register_entity(&entity);
-            });
-        entity
+    /// Register several new Entities at once, growing the archetypes' entity locations only once.
+    /// ComponentPools don't need to know about new Entities: their pages are allocated when a component is first added.
+    fn register_entities(
+        &mut self,
+        entities: &[Entity],
+    ) {
+        self.archetypes.get_mut().register_entities(entities);
     }
 
-    /// Destroy an Entity.
-    /// Note: to save time, will not deregister this Entity in any ComponentPools.
-    /// Make sure you check Entity validity before any access!
+    fn register_entity(
+        &mut self,
+        entity: &Entity,
+    ) {
+        self.archetypes.get_mut().register_entity(entity);
+    }
+
+    /// Create a new Entity for every Bundle, like `spawn`, returning them in the same order.
+    /// Entity IDs and room for the components are reserved up front, so storage grows once per batch
+    /// rather than once per Entity.
+    /// Only fails if there aren't enough free Entity IDs, in which case no Entity is created.
+    pub fn spawn_batch<T, I>(
+        &mut self,
+        bundles: I,
+    ) -> Result<Vec<Entity>, EntityComponentError>
+    where
+        T: Bundle + 'static,
+        I: IntoIterator<Item = T>,
+    {
+        self.flush_entities();
+        // Register any new component types up front, so that room can be made for them below
+        T::register_components(self);
+        let bundles: Vec<T> = bundles.into_iter().collect();
+        let entities = self.entity_allocator
+            .allocate_batch(bundles.len())
+            .map_err(| _ | EntityComponentError::Exhausted)?;
+        self.register_entities(&entities);
+
+        // Every Entity ends up in the archetype with the bundle's Table components, so make room there
+        let mut type_ids = Vec::new();
+        T::component_type_ids(&mut type_ids);
+        let table_type_ids = type_ids
+            .iter()
+            .filter(| type_id | self.component_pools.get_storage_type(type_id) == Some(StorageType::Table))
+            .copied()
+            .collect();
+        let archetypes = self.archetypes.get_mut();
+        let archetype_id = archetypes.get_or_create(table_type_ids);
+        archetypes.reserve(archetype_id, entities.len());
+        for type_id in &type_ids {
+            if let Some(component_entry) = self.component_pools.map.get_mut(type_id) {
+                component_entry.storage.get_mut().reserve(archetype_id, entities.len());
+            }
+        }
+
+        for (entity, bundle) in entities.iter().zip(bundles) {
+            bundle
+                .add_components(self, entity)
+                .expect("Couldn't add a component to a newly spawned Entity!");
+        }
+        Ok(entities)
+    }
+
+    /// Destroy an Entity, removing (and dropping) all of its components.
+    /// Its children are not destroyed: they are detached, becoming roots (see World::set_parent).
+    /// To destroy an Entity along with all of its descendants, use `despawn_recursive` instead.
     pub fn destroy_entity(
         &mut self,
         entity: Entity,
     ) {
+        self.flush_entities();
+        if self.entity_allocator.is_valid(&entity) {
+            self.detach_from_hierarchy(&entity);
+            let archetypes = self.archetypes.get_mut();
+            let location = archetypes.get_location(&entity);
+            let mut removed_type_ids = archetypes.get(location.archetype).get_component_types().to_vec();
+            for type_id in &removed_type_ids {
+                let component_entry = self.component_pools.map.get_mut(type_id).unwrap();
+                component_entry.storage.get_mut().remove_row(location);
+                component_entry.removed.get_mut().send(entity);
+            }
+            archetypes.remove_entity(&entity);
+            for (type_id, component_entry) in &mut self.component_pools.map {
+                if component_entry.storage_type == StorageType::SparseSet
+                    && component_entry.storage.get_mut().remove_entity(&entity) {
+                    component_entry.removed.get_mut().send(entity);
+                    removed_type_ids.push(*type_id);
+                }
+            }
+            // Hooks run once every component is gone, so they never see a partially destroyed Entity
+            for type_id in &removed_type_ids {
+                self.run_component_hooks(type_id, HookKind::Remove, entity);
+            }
+        }
         // Won't error if the Entity is not alive, will just log
         match self.entity_allocator.deallocate(entity){
             Ok(()) => (),
             Err(EntityError::OutOfBounds) => println!("Tried to deallocate an Entity with an out-of-bounds ID!"),
             Err(EntityError::InvalidEntity) => println!("Tried to deallocate an invalid Entity!"),
+            Err(EntityError::Exhausted) => unreachable!("Deallocating an Entity can't exhaust the allocator!"),
         }
     }
 
-    pub fn register_component<T: 'static>(&mut self) {
-        self.component_pools.insert(
-            ComponentPool::<T> {
-                all_entities: vec![None; self.entity_allocator.get_num_entries()],
-                entities_with_component: Vec::new(),
-                components: Vec::new(),
-            }
+    /// Register a component type, storing it in a ComponentPool (sparse set) sized for common components.
+    /// Components are registered on first use if needed (e.g., by `add_component`), so registering is only required
+    /// to choose how they are stored. Registering an already-registered type keeps its hooks and any components
+    /// it has, but panics if Entities already have one and the StorageType differs (see ComponentMap::insert).
+    pub fn register_component<T: Component>(&mut self) {
+        self.register_component_with_storage::<T>(StorageType::SparseSet);
+    }
+
+    /// Register a component type, storing it in a ComponentPool (sparse set) sized by `hint`.
+    /// Use StorageHint::Rare for components only a handful of Entities will ever have. See StorageHint.
+    pub fn register_component_with_hint<T: Component>(
+        &mut self,
+        hint: StorageHint,
+    ) {
+        self.component_pools.insert::<T, _>(
+            StorageType::SparseSet,
+            ComponentPool::<T>::new(hint),
         );
     }
 
-    fn get_component_pool<T: 'static>(&self) -> Result<Ref<ComponentPool<T>>, EntityComponentError> {
-        match self.component_pools.get_typed::<ComponentPool<T>>() {
+    /// Register a component type, choosing how it will be stored. See StorageType.
+    pub fn register_component_with_storage<T: Component>(
+        &mut self,
+        storage_type: StorageType,
+    ) {
+        match storage_type {
+            StorageType::SparseSet => self.register_component_with_hint::<T>(StorageHint::Common),
+            StorageType::Table => self.component_pools.insert::<T, _>(
+                storage_type,
+                ComponentColumns::<T>::new(),
+            ),
+        }
+    }
+
+    pub fn is_component_registered<T: Component>(&self) -> bool {
+        self.component_pools.map.contains_key(&TypeId::of::<T>())
+    }
+
+    fn get_storage_type<T: Component>(&self) -> Result<StorageType, EntityComponentError> {
+        match self.component_pools.get_storage_type(&TypeId::of::<T>()) {
+            Some(storage_type) => Ok(storage_type),
+            None => Err(EntityComponentError::UnregisteredComponent)
+        }
+    }
+
+    fn get_component_pool<T: Component>(&self) -> Result<Ref<'_, ComponentPool<T>>, EntityComponentError> {
+        match self.component_pools.get_typed::<T, ComponentPool<T>>() {
             Some(pool) => Ok(pool),
             None => Err(EntityComponentError::UnregisteredComponent)
         }
     }
 
-    fn get_component_pool_mut<T: 'static>(&self) -> Result<RefMut<ComponentPool<T>>, EntityComponentError> {
-        match self.component_pools.get_typed_mut::<ComponentPool<T>>() {
+    fn get_component_pool_mut<T: Component>(&self) -> Result<RefMut<'_, ComponentPool<T>>, EntityComponentError> {
+        match self.component_pools.get_typed_mut::<T, ComponentPool<T>>() {
             Some(pool) => Ok(pool),
             None => Err(EntityComponentError::UnregisteredComponent)
         }
     }
 
+    fn get_component_columns<T: Component>(&self) -> Result<Ref<'_, ComponentColumns<T>>, EntityComponentError> {
+        match self.component_pools.get_typed::<T, ComponentColumns<T>>() {
+            Some(columns) => Ok(columns),
+            None => Err(EntityComponentError::UnregisteredComponent)
+        }
+    }
+
+    fn get_component_columns_mut<T: Component>(&self) -> Result<RefMut<'_, ComponentColumns<T>>, EntityComponentError> {
+        match self.component_pools.get_typed_mut::<T, ComponentColumns<T>>() {
+            Some(columns) => Ok(columns),
+            None => Err(EntityComponentError::UnregisteredComponent)
+        }
+    }
+
+    /// Get the cell holding T's storage, without borrowing it. See TicksFetch.
+    fn get_component_storage_cell<T: Component>(&self) -> Option<&BorrowCell<Box<dyn ComponentStorage>>> {
+        self.component_pools.map
+            .get(&TypeId::of::<T>())
+            .map(| component_entry | &component_entry.storage)
+    }
+
     /// Get the component of type T for a specific Entity, if it exists.
-    pub fn get_component<T: 'static>(
+    pub fn get_component<T: Component>(
         &self,
         entity: &Entity,
-    ) -> Result<Option<Ref<T>>, EntityComponentError> {
+    ) -> Result<Option<Ref<'_, T>>, EntityComponentError> {
         // First check if this entity is valid
         if !self.entity_allocator.is_valid(entity) {
             return Err(EntityComponentError::InvalidEntity)
         }
-        let component_pool = self.get_component_pool::<T>()?;
-        // We can access directly here (without get) because we are confident that the entity exists and is valid
-        match component_pool.all_entities[entity.id as usize] {
-            // If the value in all_entities is Some, we have our index for the component!
-            Some(dense_data_index) => Ok({
-                Ref::filter_map(component_pool, | pool_ref | {
-                    Some(&pool_ref.components[dense_data_index])
-                }).ok()
-            }),
-            None => Ok(None),
+        match self.get_storage_type::<T>()? {
+            StorageType::SparseSet => {
+                let component_pool = self.get_component_pool::<T>()?;
+                match component_pool.get_dense_index(entity) {
+                    // If the value in `sparse` is Some, we have our index for the component!
+                    Some(dense_data_index) => Ok({
+                        Ref::filter_map(component_pool, | pool_ref | {
+                            Some(&pool_ref.components[dense_data_index])
+                        }).ok()
+                    }),
+                    None => Ok(None),
+                }
+            },
+            StorageType::Table => {
+                let location = self.archetypes.borrow().get_location(entity);
+                let component_columns = self.get_component_columns::<T>()?;
+                // If the Entity's archetype doesn't have a T, its column will be empty
+                Ok(Ref::filter_map(component_columns, | columns_ref | {
+                    columns_ref.get(location)
+                }).ok())
+            },
+        }
+    }
+
+    /// Check whether a specific Entity has a component of type T, without borrowing the component.
+    pub fn has_component<T: Component>(
+        &self,
+        entity: &Entity,
+    ) -> Result<bool, EntityComponentError> {
+        // First check if this entity is valid
+        if !self.entity_allocator.is_valid(entity) {
+            return Err(EntityComponentError::InvalidEntity)
+        }
+        match self.get_storage_type::<T>()? {
+            StorageType::SparseSet => {
+                let component_pool = self.get_component_pool::<T>()?;
+                Ok(component_pool.get_dense_index(entity).is_some())
+            },
+            StorageType::Table => {
+                let archetypes = self.archetypes.borrow();
+                let location = archetypes.get_location(entity);
+                Ok(archetypes.get(location.archetype).has_component_type(&TypeId::of::<T>()))
+            },
         }
     }
 
-    pub fn get_component_mut<T: 'static>(
+    pub fn get_component_mut<T: Component>(
         &self,
         entity: &Entity,
-    ) -> Result<Option<RefMut<T>>, EntityComponentError> {
+    ) -> Result<Option<RefMut<'_, T>>, EntityComponentError> {
         // First check if this entity is valid
         if !self.entity_allocator.is_valid(entity) {
             return Err(EntityComponentError::InvalidEntity)
         }
-        let component_pool = self.get_component_pool_mut::<T>()?;
-        // We can access directly here (without get) because we are confident that the entity exists and is valid
-        match component_pool.all_entities[entity.id as usize] {
-            // If the value in all_entities is Some, we have our index for the component!
-            // Some(dense_data_index) => Ok(Some(&mut component_pool.components[dense_data_index])),
-            Some(dense_data_index) => Ok({
-                RefMut::filter_map(component_pool, | pool_ref_mut | {
-                    Some(&mut pool_ref_mut.components[dense_data_index])
-                }).ok()
-            }),
-            None => Ok(None),
+        match self.get_storage_type::<T>()? {
+            StorageType::SparseSet => {
+                let mut component_pool = self.get_component_pool_mut::<T>()?;
+                match component_pool.get_dense_index(entity) {
+                    // If the value in `sparse` is Some, we have our index for the component!
+                    Some(dense_data_index) => Ok({
+                        let changed = NonNull::from(&mut component_pool.ticks[dense_data_index].changed);
+                        let component = RefMut::map(component_pool, | pool_ref_mut | {
+                            &mut pool_ref_mut.components[dense_data_index]
+                        });
+                        // SAFETY: the ticks are borrowed along with the rest of the pool, for as long as the RefMut is alive
+                        Some(unsafe { RefMut::set_changed_tick(component, changed, self.get_change_tick()) })
+                    }),
+                    None => Ok(None),
+                }
+            },
+            StorageType::Table => {
+                let location = self.archetypes.borrow().get_location(entity);
+                let mut component_columns = self.get_component_columns_mut::<T>()?;
+                // If the Entity's archetype doesn't have a T, its column will be empty
+                let Some(changed) = component_columns.get_ticks_mut(location).map(| ticks | NonNull::from(&mut ticks.changed)) else {
+                    return Ok(None)
+                };
+                let component = RefMut::map(component_columns, | columns_ref_mut | {
+                    columns_ref_mut.get_mut(location).unwrap()
+                });
+                // SAFETY: the ticks are borrowed along with the rest of the columns, for as long as the RefMut is alive
+                Ok(Some(unsafe { RefMut::set_changed_tick(component, changed, self.get_change_tick()) }))
+            },
         }
     }
 
     /// Get Entities and references to components matching a given Query.
+    /// Each component in the Query is borrowed mutably or immutably depending on
+    /// whether it was requested as `&mut T` or `&T`.
     /// See the Query trait and its implementations.
-    pub fn query<'a, Q: Query<'a>>(&'a self) -> impl Iterator<Item = (Entity, Q::QueryResult)> {
-        // Get all Entities from the component pool in the query with the fewest components
-        let entities_with_component = Q::get_component_types()
-            .into_iter()
-            .map(| type_id | {
-                self.component_pools.map
-                    .get(&type_id)
-                    .map(| cell | {
-                        let entities_with_this_component = cell
-                            .borrow()
-                            .get_entities_with_component()
-                            .clone();
-                        (entities_with_this_component.len(), entities_with_this_component)
-                    })
-                    .unwrap() // TODO: Handle errors properly---this is going to panic on unregistered component
-            })
-            .min_by_key(| &(length, _) | length)
-            .map(|(_, entities_with_component)| entities_with_component)
-            .unwrap_or_else(Vec::new); // If all `entities_with_this_component` were empty, there is no smallest one---so just get an empty Vec
-            
-        entities_with_component
-            .into_iter()
-            .filter_map(| entity | {
-                    Q::execute(self, &entity)
-                        .map(| query_result | { (entity, query_result) })
-                })
-    }
-
-    /// Get Entities and mutable references to components matching a given Query.
-    /// See the Query trait and its implementations.
-    pub fn query_mut<'a, Q: Query<'a>>(&'a self) -> impl Iterator<Item = (Entity, Q::QueryResultMut)> {
-        // Get all Entities from the component pool in the query with the fewest components
-        let entities_with_component = Q::get_component_types()
-            .into_iter()
-            .map(| type_id | {
-                self.component_pools.map
-                    .get(&type_id)
-                    .map(| cell | {
-                        let entities_with_this_component = cell
-                            .borrow()
-                            .get_entities_with_component()
-                            .clone();
-                        (entities_with_this_component.len(), entities_with_this_component)
-                    })
-                    .unwrap() // TODO: Handle errors properly---this is going to panic on unregistered component
-            })
-            .min_by_key(| &(length, _) | length)
-            .map(|(_, entities_with_component)| entities_with_component)
-            .unwrap_or_else(Vec::new); // If all `entities_with_this_component` were empty, there is no smallest one---so just get an empty Vec
-            
-        entities_with_component
-            .into_iter()
-            .filter_map(| entity | {
-                    Q::execute_mut(self, &entity)
-                        .map(| query_result | { (entity, query_result) })
-                })
-    }
-
-    pub fn add_component<T: 'static>(
-        &self,
+    /// Outside of systems, there is no last run to detect changes since,
+    /// so Added and Changed match every component.
+    pub fn query<Q: Query>(&self) -> QueryIter<'_, Q> {
+        QueryIter::new(self, SystemTicks { last_run: 0, this_run: self.get_change_tick() })
+    }
+
+    /// Add a component to an Entity, replacing (and dropping) its old one if it already had one.
+    /// Runs the component type's `on_add` or `on_replace` hooks.
+    /// If the component type isn't registered yet, it is registered with the default storage (see `register_component`).
+    pub fn add_component<T: Component>(
+        &mut self,
         entity: &Entity,
         component: T,
     ) -> Result<(), EntityComponentError> {
@@ -431,24 +1035,87 @@ impl World {
         if !self.entity_allocator.is_valid(entity) {
             return Err(EntityComponentError::InvalidEntity)
         }
+        if !self.is_component_registered::<T>() {
+            self.register_component::<T>();
+        }
+        let hook_kind = match self.get_storage_type::<T>()? {
+            StorageType::SparseSet => self.add_pool_component(entity, component)?,
+            StorageType::Table => self.add_table_component(entity, component)?,
+        };
+        self.run_component_hooks(&TypeId::of::<T>(), hook_kind, *entity);
+        Ok(())
+    }
+
+    /// Returns which hooks to run: Add if the Entity didn't have the component, or Replace if it did.
+    fn add_pool_component<T: Component>(
+        &self,
+        entity: &Entity,
+        component: T,
+    ) -> Result<HookKind, EntityComponentError> {
         let mut component_pool = self.get_component_pool_mut::<T>()?;
-        match component_pool.all_entities[entity.id as usize] {
-            // If the value in `all_entities` is Some, the Entity already has this component
-            Some(_) => {
-                println!("Tried to add a component to an entity that already had it!");
-                Ok(())
+        match component_pool.get_dense_index(entity) {
+            // If the value in `sparse` is Some, the Entity already has this component, so replace it
+            Some(entities_with_component_index) => {
+                component_pool.components[entities_with_component_index] = component;
+                component_pool.ticks[entities_with_component_index].changed = self.get_change_tick();
+                Ok(HookKind::Replace)
             },
             None => {
                 component_pool.entities_with_component.push(*entity);
                 component_pool.components.push(component);
+                component_pool.ticks.push(ComponentTicks::new(self.get_change_tick()));
                 let entities_with_component_index = component_pool.entities_with_component.len() - 1;
-                component_pool.all_entities[entity.id as usize] = Some(entities_with_component_index);
-                Ok(())
+                component_pool.sparse.insert(entity.get_id() as usize, entities_with_component_index);
+                Ok(HookKind::Add)
             },
         }
     }
 
-    pub fn remove_component<T: 'static>(
+    /// Add a Table component by moving the Entity (and all of its Table components)
+    /// into the archetype with the component's type added.
+    /// If the Entity already has the component, it is replaced in place instead.
+    fn add_table_component<T: Component>(
+        &self,
+        entity: &Entity,
+        component: T,
+    ) -> Result<HookKind, EntityComponentError> {
+        let mut component_columns = self.get_component_columns_mut::<T>()?;
+        let mut archetypes = self.archetypes.borrow_mut();
+        let location = archetypes.get_location(entity);
+        let archetype = archetypes.get(location.archetype);
+        if archetype.has_component_type(&TypeId::of::<T>()) {
+            *component_columns.get_mut(location).unwrap() = component;
+            component_columns.get_ticks_mut(location).unwrap().changed = self.get_change_tick();
+            return Ok(HookKind::Replace)
+        }
+
+        let mut new_component_types = archetype.get_component_types().to_vec();
+        new_component_types.push(TypeId::of::<T>());
+        let new_archetype_id = archetypes.get_or_create(new_component_types);
+
+        for type_id in archetypes.get(location.archetype).get_component_types() {
+            self.component_pools.get_mut(type_id).unwrap().move_row(location, new_archetype_id);
+        }
+        component_columns.push(new_archetype_id, component, ComponentTicks::new(self.get_change_tick()));
+        archetypes.move_entity(entity, new_archetype_id);
+        Ok(HookKind::Add)
+    }
+
+    /// Remove a component of type T from an Entity.
+    /// Parent and Children can't be removed this way, see World::remove_parent.
+    pub fn remove_component<T: Component>(
+        &self,
+        entity: &Entity,
+    ) -> Result<(), EntityComponentError> {
+        if TypeId::of::<T>() == TypeId::of::<Parent>() || TypeId::of::<T>() == TypeId::of::<Children>() {
+            return Err(EntityComponentError::HierarchyComponent)
+        }
+        self.remove_any_component::<T>(entity)
+    }
+
+    /// Like `remove_component`, but can also remove Parent and Children.
+    /// Only for the hierarchy, which keeps both sides consistent itself.
+    fn remove_any_component<T: Component>(
         &self,
         entity: &Entity,
     ) -> Result<(), EntityComponentError> {
@@ -456,32 +1123,24 @@ impl World {
         if !self.entity_allocator.is_valid(entity) {
             return Err(EntityComponentError::InvalidEntity)
         }
+        match self.get_storage_type::<T>()? {
+            StorageType::SparseSet => self.remove_pool_component::<T>(entity),
+            StorageType::Table => self.remove_table_component::<T>(entity),
+        }
+    }
+
+    fn remove_pool_component<T: Component>(
+        &self,
+        entity: &Entity,
+    ) -> Result<(), EntityComponentError> {
         let mut component_pool: RefMut<'_, ComponentPool<T>> = self.get_component_pool_mut::<T>()?;
-        match component_pool.all_entities[entity.id as usize] {
-            Some(entities_with_component_index) => {
-                let num_entities_with_component = component_pool.entities_with_component.len();
-                if num_entities_with_component > 0 {
-                    // Swap component to be removed with last component
-                    // (minimises number of changes that need to be made to `all_entities`
-                    component_pool.entities_with_component.swap(entities_with_component_index, num_entities_with_component - 1);
-                    component_pool.components.swap(entities_with_component_index, num_entities_with_component - 1);
-    
-                    // Modify entry in `all_entities` for swapped component
-                    let swapped_entity_id = component_pool.entities_with_component[entities_with_component_index];
-                    component_pool.all_entities[swapped_entity_id.id as usize] = Some(entities_with_component_index);
-    
-                    // Remove last component
-                    component_pool.entities_with_component.remove(num_entities_with_component - 1);
-                    component_pool.components.remove(num_entities_with_component - 1);
-                } else {
-                    component_pool.entities_with_component.remove(entities_with_component_index);
-                    component_pool.components.remove(entities_with_component_index);
-                }
-                // Set entry in `all_entities` to None
-                component_pool.all_entities[entity.id as usize] = None;
+        match component_pool.swap_remove(entity) {
+            Some(_) => {
+                self.record_removed_component::<T>(entity);
+                self.run_component_hooks(&TypeId::of::<T>(), HookKind::Remove, *entity);
                 Ok(())
             },
-            // If the value in `all_entities` is None, the Entity does not have this component
+            // If the value in `sparse` is None, the Entity does not have this component
             None => {
                 println!("Tried to remove a component from an entity that did not have it!");
                 Ok(())
@@ -489,131 +1148,388 @@ impl World {
         }
     }
 
+    /// Remove a Table component by moving the Entity (and the rest of its Table components)
+    /// into the archetype with the component's type removed.
+    fn remove_table_component<T: Component>(
+        &self,
+        entity: &Entity,
+    ) -> Result<(), EntityComponentError> {
+        let mut archetypes = self.archetypes.borrow_mut();
+        let location = archetypes.get_location(entity);
+        let archetype = archetypes.get(location.archetype);
+        if !archetype.has_component_type(&TypeId::of::<T>()) {
+            println!("Tried to remove a component from an entity that did not have it!");
+            return Ok(())
+        }
+
+        let component_types = archetype.get_component_types().to_vec();
+        let new_archetype_id = archetypes.get_or_create(
+            component_types
+                .iter()
+                .filter(| type_id | **type_id != TypeId::of::<T>())
+                .copied()
+                .collect()
+        );
+
+        for type_id in &component_types {
+            let mut component_storage = self.component_pools.get_mut(type_id).unwrap();
+            if *type_id == TypeId::of::<T>() {
+                component_storage.remove_row(location);
+            } else {
+                component_storage.move_row(location, new_archetype_id);
+            }
+        }
+        archetypes.move_entity(entity, new_archetype_id);
+        self.record_removed_component::<T>(entity);
+        self.run_component_hooks(&TypeId::of::<T>(), HookKind::Remove, *entity);
+        Ok(())
+    }
+
+    fn record_removed_component<T: Component>(
+        &self,
+        entity: &Entity,
+    ) {
+        self.component_pools.map
+            .get(&TypeId::of::<T>())
+            .expect("Removed component was not registered!")
+            .removed
+            .borrow_mut()
+            .send(*entity);
+    }
+
+    /// Get the Entities that have lost a component of type T, either because it was removed
+    /// or because they were destroyed while holding it.
+    /// Like Events, each removal is kept until the second call to `update_removed_components` after it,
+    /// so it can be read (with an EventCursor) for a whole frame.
+    /// Systems can use the RemovedComponents parameter instead, which keeps a cursor for them.
+    pub fn get_removed_components<T: Component>(&self) -> Result<Ref<'_, Events<Entity>>, EntityComponentError> {
+        match self.component_pools.map.get(&TypeId::of::<T>()) {
+            Some(component_entry) => Ok(component_entry.removed.borrow()),
+            None => Err(EntityComponentError::UnregisteredComponent),
+        }
+    }
+
+    /// Update the log of removed components for every component type. Should be called once per update;
+    /// removals are dropped after two calls.
+    pub fn update_removed_components(&self) {
+        for component_entry in self.component_pools.map.values() {
+            component_entry.removed.borrow_mut().update();
+        }
+    }
+
+    /// Register a hook to run whenever a component of type T is added to an Entity that didn't have one.
+    /// Hooks run straight after the change, in the order they were registered, and record any changes
+    /// they want to make to the World with Commands. Those commands are applied:
+    /// - straight after the command that triggered the hook, if it was triggered by a CommandQueue being applied
+    ///   (so hook commands always run before the rest of the queue), or
+    /// - at the next call to `apply_hook_commands` (e.g., at the end of a Schedule stage), otherwise.
+    ///
+    /// T is registered with the default storage if it hasn't been registered yet, like `add_component`.
+    /// It can still be registered afterwards to choose its storage, keeping its hooks, until an Entity has one.
+    /// ```
+    /// world.on_add::<TileMap>(TileMap::spawn_colliders);
+    /// world.add_component(&tile_map, TileMap::new(tiles, tile_size, collidable_tile_ids))?;
+    /// world.apply_hook_commands();
+    /// ```
+    pub fn on_add<T: Component>(
+        &mut self,
+        hook: impl Fn(Entity, &mut Commands) + Send + Sync + 'static,
+    ) {
+        self.register_component_hook::<T>(HookKind::Add, Box::new(hook))
+    }
+
+    /// Register a hook to run whenever a component of type T is added to an Entity that already had one,
+    /// replacing it. See `on_add`.
+    pub fn on_replace<T: Component>(
+        &mut self,
+        hook: impl Fn(Entity, &mut Commands) + Send + Sync + 'static,
+    ) {
+        self.register_component_hook::<T>(HookKind::Replace, Box::new(hook))
+    }
+
+    /// Register a hook to run whenever a component of type T is removed from an Entity,
+    /// including when the Entity is destroyed. The component is already gone by the time the hook runs.
+    /// See `on_add`.
+    pub fn on_remove<T: Component>(
+        &mut self,
+        hook: impl Fn(Entity, &mut Commands) + Send + Sync + 'static,
+    ) {
+        self.register_component_hook::<T>(HookKind::Remove, Box::new(hook))
+    }
+
+    fn register_component_hook<T: Component>(
+        &mut self,
+        hook_kind: HookKind,
+        hook: ComponentHook,
+    ) {
+        if !self.is_component_registered::<T>() {
+            self.register_component::<T>();
+        }
+        self.component_pools.map
+            .get_mut(&TypeId::of::<T>())
+            .expect("Component with a hook to register was not registered!")
+            .hooks
+            .push(hook_kind, hook);
+    }
+
+    fn run_component_hooks(
+        &self,
+        type_id: &TypeId,
+        hook_kind: HookKind,
+        entity: Entity,
+    ) {
+        let hooks = self.component_pools.map
+            .get(type_id)
+            .expect("Component with hooks to run was not registered!")
+            .hooks
+            .get(hook_kind);
+        if hooks.is_empty() {
+            return
+        }
+        // The queue is never left inconsistent while locked, so it is still usable if another thread panicked
+        let mut hook_commands = self.hook_commands.lock().unwrap_or_else(| error | error.into_inner());
+        let mut commands = Commands::new(&mut hook_commands, self);
+        for hook in hooks {
+            hook(entity, &mut commands);
+        }
+    }
+
+    /// Apply the commands recorded by component hooks since they were last applied. See `on_add`.
+    pub fn apply_hook_commands(&mut self) {
+        let hook_commands = self.hook_commands.get_mut().unwrap_or_else(| error | error.into_inner());
+        if hook_commands.is_empty() {
+            return
+        }
+        // Take the queue, so that hooks triggered while applying it can record into a fresh one
+        let mut hook_commands = mem::replace(hook_commands, CommandQueue::new());
+        hook_commands.apply(self);
+    }
+
+    /// Add every component in a Bundle to an Entity, stopping at the first that fails.
     pub fn add_bundle<T> (
         &mut self,
         entity: &Entity,
         bundle: T,
-    )
+    ) -> Result<(), EntityComponentError>
     where T: Bundle + 'static {
-        bundle.add_components(self, entity);
+        bundle.add_components(self, entity)
     }
-}
 
-/// Used to create Query trait objects. When used with World::query,
-/// gets all Entities that match the given Query.
-/// Note: Components in the Query **must** be registered in the World.
-/// See World::register_component.
-pub trait Query<'a> {
-    type QueryResult;
-    type QueryResultMut;
-
-    /// Returns all component types involved in this query.
-    fn get_component_types() -> Vec<TypeId>;
-    /// Execute the query for this Entity, maybe returning component(s).
-    fn execute(world: &'a World, entity: &Entity) -> Option<Self::QueryResult>;
-    fn execute_mut(world: &'a World, entity: &Entity) -> Option<Self::QueryResultMut>;
-}
+    /// Create a new Entity with every component in a Bundle, e.g., a tuple of components:
+    /// ```
+    /// let player = world.spawn((Player { }, Transform { position }))?;
+    /// ```
+    /// Only fails if every Entity ID is in use or retired. Adding the components to the new Entity can't fail,
+    /// as it is valid and any unregistered component types are registered on first use (see `add_component`).
+    pub fn spawn<T> (
+        &mut self,
+        bundle: T,
+    ) -> Result<Entity, EntityComponentError>
+    where T: Bundle + 'static {
+        let entity = self.create_entity().map_err(| _ | EntityComponentError::Exhausted)?;
+        bundle
+            .add_components(self, &entity)
+            .expect("Couldn't add a component to a newly spawned Entity!");
+        Ok(entity)
+    }
 
-/// Get all Entities that have the component A.
-/// # Examples:
-/// ```
-/// world.query::<(&Transform)>()
-///     .iter()
-///     .for_each(| entity, transform | {
-///         ...
-///     })
-/// ```
-impl <'a, A: 'static> Query<'a> for &'a A {
-    type QueryResult = Ref<'a, A>;
-    type QueryResultMut = RefMut<'a, A>;
-
-    fn get_component_types() -> Vec<TypeId> {
-        vec![TypeId::of::<ComponentPool<A>>()]
-    }
-
-    fn execute(
-        world: &'a World,
-        entity: &Entity,
-    ) -> Option<Self::QueryResult> {
-        // Since we don't remove Entities from component pools when Entities are destroyed,
-        // it's possible that queries will be executed over invalid (i.e., dead) Entities.
-        // In this case, we can just return None
-        match world.get_component::<A>(entity) {
-            Ok(component) => component,
-            Err(EntityComponentError::InvalidEntity) => None,
-            Err(EntityComponentError::UnregisteredComponent) => panic!("Component was not registered!"),
-        }
+    /// Insert a resource: a single value of type R, stored in the World rather than on an Entity.
+    /// If the World already had a resource of type R, it is replaced and returned.
+    pub fn insert_resource<R: Resource>(
+        &mut self,
+        resource: R,
+    ) -> Option<R> {
+        self.resources.insert(resource)
     }
 
-    fn execute_mut(
-        world: &'a World,
-        entity: &Entity,
-    ) -> Option<Self::QueryResultMut> {
-        // Since we don't remove Entities from component pools when Entities are destroyed,
-        // it's possible that queries will be executed over invalid (i.e., dead) Entities.
-        // In this case, we can just return None
-        match world.get_component_mut::<A>(entity) {
-            Ok(component) => component,
-            Err(EntityComponentError::InvalidEntity) => None,
-            Err(EntityComponentError::UnregisteredComponent) => panic!("Component was not registered!"),
+    /// Get the resource of type R, if it exists.
+    /// Like components, resources are borrow checked at runtime:
+    /// this will panic if the resource is already mutably borrowed.
+    pub fn get_resource<R: Resource>(&self) -> Option<Ref<'_, R>> {
+        self.resources.get::<R>()
+    }
+
+    /// Get the resource of type R mutably, if it exists.
+    /// Like components, resources are borrow checked at runtime:
+    /// this will panic if the resource is already borrowed.
+    pub fn get_resource_mut<R: Resource>(&self) -> Option<RefMut<'_, R>> {
+        self.resources.get_mut::<R>()
+    }
+
+    /// Remove the resource of type R from the World, returning it (if it existed).
+    pub fn remove_resource<R: Resource>(&mut self) -> Option<R> {
+        self.resources.remove::<R>()
+    }
+
+    /// Insert a non-send resource: a resource that can't be shared between threads.
+    /// Non-send resources can only be accessed from the thread that created the World,
+    /// so systems using them always run on that thread. Panics if called from another thread.
+    pub fn insert_non_send_resource<R: 'static>(
+        &mut self,
+        resource: R,
+    ) -> Option<R> {
+        self.non_send_resources.insert(resource)
+    }
+
+    /// Get the non-send resource of type R, if it exists. Panics if called from a thread other than the main thread.
+    pub fn get_non_send_resource<R: 'static>(&self) -> Option<Ref<'_, R>> {
+        self.non_send_resources.get::<R>()
+    }
+
+    /// Get the non-send resource of type R mutably, if it exists. Panics if called from a thread other than the main thread.
+    pub fn get_non_send_resource_mut<R: 'static>(&self) -> Option<RefMut<'_, R>> {
+        self.non_send_resources.get_mut::<R>()
+    }
+
+    /// Remove the non-send resource of type R from the World, returning it (if it existed).
+    pub fn remove_non_send_resource<R: 'static>(&mut self) -> Option<R> {
+        self.non_send_resources.remove::<R>()
+    }
+
+    /// Register an event type, inserting an empty Events<T> resource for it.
+    /// Registered Events are updated (and old events dropped) by `update_events`.
+    pub fn register_event<T: Event>(&mut self) {
+        self.insert_resource(Events::<T>::new());
+        self.event_updaters.push(Events::<T>::update_resource);
+    }
+
+    /// Send an event of type T. Panics if the event type was not registered.
+    pub fn send_event<T: Event>(
+        &self,
+        event: T,
+    ) {
+        self.get_resource_mut::<Events<T>>()
+            .expect("Tried to send an event of an unregistered type!")
+            .send(event);
+    }
+
+    /// Update every registered Events. Should be called once per update;
+    /// events are dropped after two calls.
+    pub fn update_events(&self) {
+        for event_updater in &self.event_updaters {
+            event_updater(self);
         }
     }
 }
 
-/// Get all Entities that have component A and component B.
-/// # Examples:
-/// ```
-/// world.query::<(&Transform, &Sprite)>()
-///     .iter()
-///     .for_each(| entity, (transform, sprite) | {
-///         ...
-///     })
-/// ```
-impl <'a, A: 'static, B: 'static> Query<'a> for (&'a A, &'a B) {
-    type QueryResult = (Ref<'a, A>, Ref<'a, B>);
-    type QueryResultMut = (RefMut<'a, A>, RefMut<'a, B>);
-
-    fn get_component_types() -> Vec<TypeId> {
-        vec![TypeId::of::<ComponentPool<A>>(), TypeId::of::<ComponentPool<B>>()]
-    }
-
-    fn execute(
-        world: &'a World,
-        entity: &Entity,
-    ) -> Option<Self::QueryResult> {
-        // Since we don't remove Entities from component pools when Entities are destroyed,
-        // it's possible that queries will be executed over invalid (i.e., dead) Entities.
-        // In this case, we can just return None
-        let component_a = match world.get_component::<A>(entity) {
-            Ok(component) => component,
-            Err(EntityComponentError::InvalidEntity) => None,
-            Err(EntityComponentError::UnregisteredComponent) => panic!("Component was not registered!"),
-        }?;
-        let component_b = match world.get_component::<B>(entity) {
-            Ok(component) => component,
-            Err(EntityComponentError::InvalidEntity) => None,
-            Err(EntityComponentError::UnregisteredComponent) => panic!("Component was not registered!"),
-        }?;
-        Some((component_a, component_b))
-    }
-
-    fn execute_mut(
-        world: &'a World,
-        entity: &Entity,
-    ) -> Option<Self::QueryResultMut> {
-        // Since we don't remove Entities from component pools when Entities are destroyed,
-        // it's possible that queries will be executed over invalid (i.e., dead) Entities.
-        // In this case, we can just return None
-        let component_a = match world.get_component_mut::<A>(entity) {
-            Ok(component) => component,
-            Err(EntityComponentError::InvalidEntity) => None,
-            Err(EntityComponentError::UnregisteredComponent) => panic!("Component was not registered!"),
-        }?;
-        let component_b = match world.get_component_mut::<B>(entity) {
-            Ok(component) => component,
-            Err(EntityComponentError::InvalidEntity) => None,
-            Err(EntityComponentError::UnregisteredComponent) => panic!("Component was not registered!"),
-        }?;
-        Some((component_a, component_b))
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, sync::atomic::{AtomicUsize, Ordering}, thread};
+
+    use super::{Entity, EntityAllocator, EntityError, EntityGeneration, EntityId, EventCursor, StorageType, World};
+
+    struct A;
+
+    #[test]
+    fn non_send_resources_are_leaked_when_dropped_off_the_main_thread() {
+        let resource = Rc::new(());
+        let mut world = World::new();
+        world.insert_non_send_resource(Rc::clone(&resource));
+        thread::spawn(move | | drop(world)).join().expect("Dropping the World off the main thread panicked!");
+        assert_eq!(Rc::strong_count(&resource), 2);
+    }
+
+    #[test]
+    fn hooks_added_before_registering_are_kept() {
+        static NUM_ADDED: AtomicUsize = AtomicUsize::new(0);
+        let mut world = World::new();
+        world.on_add::<A>(| _, _ | { NUM_ADDED.fetch_add(1, Ordering::SeqCst); });
+        world.register_component_with_storage::<A>(StorageType::Table);
+        let entity = world.create_entity().unwrap();
+        world.add_component(&entity, A).unwrap();
+        assert_eq!(NUM_ADDED.load(Ordering::SeqCst), 1);
+    }
+
+    struct B;
+
+    #[test]
+    fn registering_again_keeps_components() {
+        let mut world = World::new();
+        let entity = world.spawn((B,)).unwrap();
+        world.register_component::<B>();
+        assert_eq!(world.query::<&B>().count(), 1);
+        world.destroy_entity(entity);
+        assert_eq!(EventCursor::new().read(&world.get_removed_components::<B>().unwrap()).count(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn changing_storage_type_of_stored_components_panics() {
+        let mut world = World::new();
+        world.spawn((B,)).unwrap();
+        world.register_component_with_storage::<B>(StorageType::Table);
+    }
+
+    #[test]
+    fn entity_packs_id_low_and_generation_high() {
+        let entity = Entity::new(1, 2);
+        assert_eq!(entity.bits, 2 << 32 | 1);
+        assert_eq!((entity.get_id(), entity.get_generation()), (1, 2));
+        let entity = Entity::new(EntityId::MAX, EntityGeneration::MAX);
+        assert_eq!(entity.bits, u64::MAX);
+        assert_eq!((entity.get_id(), entity.get_generation()), (EntityId::MAX, EntityGeneration::MAX));
+        let entity = Entity::new(EntityId::MAX, 0);
+        assert_eq!((entity.get_id(), entity.get_generation()), (EntityId::MAX, 0));
+    }
+
+    #[test]
+    fn reserve_matches_flush_and_allocate() {
+        let mut allocator = EntityAllocator::new();
+        let entities = allocator.allocate_batch(4).unwrap();
+        allocator.deallocate(entities[1]).unwrap();
+        allocator.deallocate(entities[3]).unwrap();
+        // Two recycled IDs (most recently freed first), then two new ones
+        let reserved = (0..4).map(| _ | allocator.reserve().unwrap()).collect::<Vec<_>>();
+        assert_eq!(reserved, vec![Entity::new(3, 1), Entity::new(1, 1), Entity::new(4, 0), Entity::new(5, 0)]);
+        assert!(reserved.iter().all(| entity | !allocator.is_valid(entity)));
+        assert_eq!(allocator.flush(), reserved);
+        assert!(reserved.iter().all(| entity | allocator.is_valid(entity)));
+        // Nothing is left reserved, so the next allocation carries on after the flushed ones
+        assert_eq!(allocator.allocate().unwrap(), Entity::new(6, 0));
+    }
+
+    #[test]
+    fn reserve_fails_past_last_entity_id() {
+        let mut allocator = EntityAllocator::new();
+        let entities = allocator.allocate_batch(3).unwrap();
+        allocator.deallocate(entities[0]).unwrap();
+        // Pretend the recycled ID and every new ID before the last one (3..EntityId::MAX) have been reserved
+        *allocator.num_reserved.get_mut() = 1 + (EntityId::MAX as usize - 3);
+        assert_eq!(allocator.reserve().unwrap(), Entity::new(EntityId::MAX, 0));
+        assert!(matches!(allocator.reserve(), Err(EntityError::Exhausted)));
+        assert!(matches!(allocator.reserve(), Err(EntityError::Exhausted)));
+        // Failed reservations are undone, so only the successful ones stay reserved
+        assert_eq!(*allocator.num_reserved.get_mut(), EntityId::MAX as usize - 1);
+        *allocator.num_reserved.get_mut() = 0;
+    }
+
+    #[test]
+    fn allocate_batch_fails_without_allocating_past_last_entity_id() {
+        let mut allocator = EntityAllocator::new();
+        let entities = allocator.allocate_batch(3).unwrap();
+        allocator.deallocate(entities[0]).unwrap();
+        // One recycled ID plus every new ID, plus one too many
+        let num_entities = 1 + (EntityId::MAX as usize + 1 - 3) + 1;
+        assert!(matches!(allocator.allocate_batch(num_entities), Err(EntityError::Exhausted)));
+        assert_eq!((allocator.entries.len(), allocator.available_entity_ids.len()), (3, 1));
+        assert_eq!(allocator.allocate().unwrap(), Entity::new(0, 1));
+    }
+
+    #[test]
+    fn entity_ids_are_retired_at_last_generation() {
+        let mut allocator = EntityAllocator::new();
+        let entity = allocator.allocate().unwrap();
+        allocator.entries[0].generation = EntityGeneration::MAX - 1;
+        allocator.deallocate(Entity::new(0, EntityGeneration::MAX - 1)).unwrap();
+        let last_generation = allocator.allocate().unwrap();
+        assert_eq!(last_generation, Entity::new(0, EntityGeneration::MAX));
+        allocator.deallocate(last_generation).unwrap();
+        assert!(allocator.available_entity_ids.is_empty());
+        // The retired ID is never handed out again, so its old handles can't become valid by wrapping around
+        assert_eq!(allocator.reserve().unwrap(), Entity::new(1, 0));
+        assert_eq!(allocator.flush(), vec![Entity::new(1, 0)]);
+        assert_eq!(allocator.allocate().unwrap(), Entity::new(2, 0));
+        assert!(!allocator.is_valid(&entity));
+        assert!(!allocator.is_valid(&last_generation));
+    }
+}
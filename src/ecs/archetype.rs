@@ -0,0 +1,342 @@
+use std::{any::{Any, TypeId}, collections::HashMap, ptr::NonNull};
+
+use super::{Component, ComponentStorage, ComponentTicks, Entity};
+
+pub type ArchetypeId = usize;
+
+/// The archetype with no Table components. Every new Entity starts here.
+pub(super) const EMPTY_ARCHETYPE: ArchetypeId = 0;
+
+/// Where an Entity's Table components are stored.
+#[derive(Copy, Clone, Debug)]
+pub struct EntityLocation {
+    /// The archetype the Entity belongs to.
+    pub archetype: ArchetypeId,
+    /// The Entity's index in each of the archetype's columns.
+    pub row: usize,
+}
+
+/// A group of Entities that all have exactly the same set of Table components.
+/// The components themselves are stored in a `ComponentColumns<T>` per component type,
+/// in one column per archetype, so all components for a given row belong to the same Entity.
+pub struct Archetype {
+    /// The Table component types stored in this archetype, sorted.
+    component_types: Vec<TypeId>,
+    /// The Entity stored in each row of this archetype's columns.
+    entities: Vec<Entity>,
+}
+
+impl Archetype {
+    pub fn get_component_types(&self) -> &[TypeId] {
+        &self.component_types
+    }
+
+    pub fn get_entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    pub fn has_component_type(
+        &self,
+        type_id: &TypeId,
+    ) -> bool {
+        self.component_types.binary_search(type_id).is_ok()
+    }
+}
+
+/// Keeps track of every archetype, and which archetype (and row) each Entity is stored in.
+pub(super) struct Archetypes {
+    archetypes: Vec<Archetype>,
+    /// Maps from a sorted set of Table component types to the archetype storing that set.
+    archetype_ids: HashMap<Vec<TypeId>, ArchetypeId>,
+    /// The length of this Vec equals the number of entries in the EntityAllocator.
+    /// The index of each entry corresponds to the Entity with id=index.
+    /// Entries for dead Entities are left stale until their ID is recycled.
+    locations: Vec<EntityLocation>,
+}
+
+impl Archetypes {
+    pub fn new() -> Archetypes {
+        let mut archetype_ids = HashMap::new();
+        archetype_ids.insert(Vec::new(), EMPTY_ARCHETYPE);
+        Archetypes {
+            archetypes: vec![Archetype { component_types: Vec::new(), entities: Vec::new() }],
+            archetype_ids,
+            locations: Vec::new(),
+        }
+    }
+
+    pub fn get(
+        &self,
+        archetype_id: ArchetypeId,
+    ) -> &Archetype {
+        &self.archetypes[archetype_id]
+    }
+
+    pub fn try_get(
+        &self,
+        archetype_id: ArchetypeId,
+    ) -> Option<&Archetype> {
+        self.archetypes.get(archetype_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Archetype> {
+        self.archetypes.iter()
+    }
+
+    pub fn get_location(
+        &self,
+        entity: &Entity,
+    ) -> EntityLocation {
+        self.locations[entity.get_id() as usize]
+    }
+
+    /// Get the archetype storing exactly `component_types`, creating it if it doesn't exist yet.
+    pub fn get_or_create(
+        &mut self,
+        mut component_types: Vec<TypeId>,
+    ) -> ArchetypeId {
+        component_types.sort();
+        if let Some(archetype_id) = self.archetype_ids.get(&component_types) {
+            return *archetype_id
+        }
+        let archetype_id = self.archetypes.len();
+        self.archetype_ids.insert(component_types.clone(), archetype_id);
+        self.archetypes.push(Archetype { component_types, entities: Vec::new() });
+        archetype_id
+    }
+
+    /// Place a newly created Entity (with no Table components) in the empty archetype.
+    pub fn register_entity(
+        &mut self,
+        entity: &Entity,
+    ) {
+        let location = self.push(EMPTY_ARCHETYPE, entity);
+        let index = entity.get_id() as usize;
+        if self.locations.len() <= index {
+            self.locations.resize(index + 1, location);
+        }
+        self.locations[index] = location;
+    }
+
+    /// Place several newly created Entities in the empty archetype, growing `locations` only once.
+    pub fn register_entities(
+        &mut self,
+        entities: &[Entity],
+    ) {
+        if let Some(highest_id) = entities.iter().map(| entity | entity.get_id() as usize).max()
+            && self.locations.len() <= highest_id {
+            self.locations.resize(highest_id + 1, EntityLocation { archetype: EMPTY_ARCHETYPE, row: 0 });
+        }
+        self.archetypes[EMPTY_ARCHETYPE].entities.reserve(entities.len());
+        for entity in entities {
+            self.register_entity(entity);
+        }
+    }
+
+    /// Make room for `additional` more Entities in an archetype without reallocating.
+    pub fn reserve(
+        &mut self,
+        archetype_id: ArchetypeId,
+        additional: usize,
+    ) {
+        self.archetypes[archetype_id].entities.reserve(additional);
+    }
+
+    /// Move an Entity's row to another archetype.
+    /// Note: this only updates the archetypes! The caller is responsible for
+    /// moving the Entity's components between columns to match.
+    pub fn move_entity(
+        &mut self,
+        entity: &Entity,
+        new_archetype_id: ArchetypeId,
+    ) {
+        self.swap_remove(entity);
+        self.locations[entity.get_id() as usize] = self.push(new_archetype_id, entity);
+    }
+
+    /// Remove an Entity from its archetype entirely (e.g., when it is destroyed).
+    /// Note: this only updates the archetypes! The caller is responsible for
+    /// removing the Entity's components from their columns to match.
+    pub fn remove_entity(
+        &mut self,
+        entity: &Entity,
+    ) {
+        self.swap_remove(entity);
+    }
+
+    fn push(
+        &mut self,
+        archetype_id: ArchetypeId,
+        entity: &Entity,
+    ) -> EntityLocation {
+        let archetype = &mut self.archetypes[archetype_id];
+        archetype.entities.push(*entity);
+        EntityLocation { archetype: archetype_id, row: archetype.entities.len() - 1 }
+    }
+
+    /// Swap-remove an Entity's row, mirroring the swap-remove done on each column.
+    fn swap_remove(
+        &mut self,
+        entity: &Entity,
+    ) {
+        let location = self.locations[entity.get_id() as usize];
+        let entities = &mut self.archetypes[location.archetype].entities;
+        entities.swap_remove(location.row);
+        // The last Entity in the archetype was swapped into the removed row, so update its location
+        if let Some(swapped_entity) = entities.get(location.row) {
+            self.locations[swapped_entity.get_id() as usize].row = location.row;
+        }
+    }
+}
+
+/// Stores every component of type <T> that uses Table storage,
+/// split into one column per archetype.
+pub(super) struct ComponentColumns<T> {
+    /// The index of each column corresponds to the archetype with id=index.
+    /// Columns of archetypes without a <T> are always empty.
+    /// Each column is parallel with its archetype's Entities.
+    columns: Vec<Vec<T>>,
+    /// When each component was added and last changed.
+    /// Each column of ticks is parallel with the corresponding column of components.
+    ticks: Vec<Vec<ComponentTicks>>,
+    /// Pointers to each column, refreshed by `get_column_ptrs`.
+    /// Kept between queries, so that refreshing them doesn't allocate.
+    column_ptrs: Vec<ColumnPtrs<T>>,
+}
+
+// SAFETY: `column_ptrs` only point into the columns, which ComponentColumns owns,
+// and are only dereferenced by a query that has the columns mutably borrowed (see `get_column_ptrs`)
+unsafe impl<T: Send> Send for ComponentColumns<T> {}
+unsafe impl<T: Sync> Sync for ComponentColumns<T> {}
+
+/// Pointers to the start of an archetype's column of components and its column of ticks,
+/// along with the number of rows in the columns.
+pub(super) struct ColumnPtrs<T> {
+    pub components: NonNull<T>,
+    pub ticks: NonNull<ComponentTicks>,
+    pub len: usize,
+}
+
+impl<T> ComponentColumns<T> {
+    pub fn new() -> ComponentColumns<T> {
+        ComponentColumns {
+            columns: Vec::new(),
+            ticks: Vec::new(),
+            column_ptrs: Vec::new(),
+        }
+    }
+
+    pub fn get(
+        &self,
+        location: EntityLocation,
+    ) -> Option<&T> {
+        self.columns.get(location.archetype)?.get(location.row)
+    }
+
+    pub fn get_mut(
+        &mut self,
+        location: EntityLocation,
+    ) -> Option<&mut T> {
+        self.columns.get_mut(location.archetype)?.get_mut(location.row)
+    }
+
+    /// Copy out a component's ticks without creating a reference into its column of ticks.
+    /// Used while the components' RefMuts may be writing to other ticks in the column (see get_column_ptrs).
+    pub fn read_ticks(
+        &self,
+        location: EntityLocation,
+    ) -> Option<ComponentTicks> {
+        let ticks = self.ticks.get(location.archetype)?;
+        // SAFETY: the row was just checked to be in bounds
+        (location.row < ticks.len()).then(| | unsafe { ticks.as_ptr().add(location.row).read() })
+    }
+
+    pub fn get_ticks_mut(
+        &mut self,
+        location: EntityLocation,
+    ) -> Option<&mut ComponentTicks> {
+        self.ticks.get_mut(location.archetype)?.get_mut(location.row)
+    }
+
+    /// Get pointers to every column (indexed by archetype ID), without borrowing the columns again.
+    /// Used by queries to hand out mutable references to many components at once, on several threads.
+    /// The pointers stay valid until the columns are next modified.
+    pub fn get_column_ptrs(&mut self) -> &[ColumnPtrs<T>] {
+        self.column_ptrs.clear();
+        for (column, ticks) in self.columns.iter_mut().zip(self.ticks.iter_mut()) {
+            // SAFETY: a Vec's pointer is never null, even when it hasn't allocated
+            self.column_ptrs.push(unsafe { ColumnPtrs {
+                components: NonNull::new_unchecked(column.as_mut_ptr()),
+                ticks: NonNull::new_unchecked(ticks.as_mut_ptr()),
+                len: column.len(),
+            }});
+        }
+        &self.column_ptrs
+    }
+
+    /// Push a component (and its ticks) onto the end of an archetype's column.
+    pub fn push(
+        &mut self,
+        archetype_id: ArchetypeId,
+        component: T,
+        ticks: ComponentTicks,
+    ) {
+        self.add_columns(archetype_id);
+        self.columns[archetype_id].push(component);
+        self.ticks[archetype_id].push(ticks);
+    }
+
+    /// Make sure there is a column for every archetype up to and including `archetype_id`.
+    fn add_columns(
+        &mut self,
+        archetype_id: ArchetypeId,
+    ) {
+        if self.columns.len() <= archetype_id {
+            self.columns.resize_with(archetype_id + 1, Vec::new);
+            self.ticks.resize_with(archetype_id + 1, Vec::new);
+        }
+    }
+
+    /// Swap-remove a component (and its ticks) from an archetype's column, returning them.
+    pub fn swap_remove(
+        &mut self,
+        location: EntityLocation,
+    ) -> (T, ComponentTicks) {
+        let ticks = self.ticks[location.archetype].swap_remove(location.row);
+        (self.columns[location.archetype].swap_remove(location.row), ticks)
+    }
+}
+
+impl<T: Component> ComponentStorage for ComponentColumns<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn remove_entity(&mut self, _entity: &Entity) -> bool {
+        unreachable!("ComponentColumns are stored in archetypes, use remove_row!")
+    }
+
+    fn reserve(&mut self, archetype_id: ArchetypeId, additional: usize) {
+        self.add_columns(archetype_id);
+        self.columns[archetype_id].reserve(additional);
+        self.ticks[archetype_id].reserve(additional);
+    }
+
+    fn move_row(&mut self, location: EntityLocation, new_archetype_id: ArchetypeId) {
+        // Moving a component between archetypes doesn't count as changing it, so its ticks move with it
+        let (component, ticks) = self.swap_remove(location);
+        self.push(new_archetype_id, component, ticks);
+    }
+
+    fn remove_row(&mut self, location: EntityLocation) {
+        self.swap_remove(location);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.columns.iter().all(Vec::is_empty)
+    }
+}
@@ -0,0 +1,347 @@
+use std::{cell::UnsafeCell, marker::PhantomData, ops::{Deref, DerefMut}, ptr::NonNull, sync::atomic::{AtomicIsize, Ordering}};
+
+use super::change_detection::Tick;
+
+/// Positive values count the number of live Refs.
+/// Negative values count the number of live RefMuts (each to a disjoint part of the value).
+type BorrowFlag = isize;
+
+const UNUSED: BorrowFlag = 0;
+
+/// A mutable memory location with dynamically checked borrow rules, like `std::cell::RefCell`.
+/// Unlike RefCell, a single mutable borrow can be shared between several RefMuts pointing to
+/// disjoint parts of the value (see `RefMut::map_split_unchecked`), so a query can hand out a
+/// RefMut per component while only borrowing the component pool once.
+/// The borrow flag is atomic, so a BorrowCell can be shared between threads (like `std::sync::RwLock`,
+/// but a conflicting borrow panics rather than blocking).
+pub struct BorrowCell<T> {
+    borrow: AtomicIsize,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the value can only be reached through borrows, which are checked atomically.
+// Shared borrows from several threads need T: Sync; mutable borrows (and moving the cell) need T: Send
+unsafe impl<T: Send> Send for BorrowCell<T> {}
+unsafe impl<T: Send + Sync> Sync for BorrowCell<T> {}
+
+impl<T> BorrowCell<T> {
+    pub fn new(value: T) -> BorrowCell<T> {
+        BorrowCell {
+            borrow: AtomicIsize::new(UNUSED),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Immutably borrow the value. Panics if the value is currently mutably borrowed.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        match BorrowRef::new(&self.borrow) {
+            Some(borrow) => Ref {
+                // SAFETY: UnsafeCell::get never returns a null pointer
+                value: unsafe { NonNull::new_unchecked(self.value.get()) },
+                borrow,
+            },
+            None => panic!("Tried to borrow a value that was already mutably borrowed!"),
+        }
+    }
+
+    /// Mutably borrow the value. Panics if the value is currently borrowed.
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        match BorrowRefMut::new(&self.borrow) {
+            Some(borrow) => RefMut {
+                // SAFETY: UnsafeCell::get never returns a null pointer
+                value: unsafe { NonNull::new_unchecked(self.value.get()) },
+                borrow,
+                changed_tick: None,
+                marker: PhantomData,
+            },
+            None => panic!("Tried to mutably borrow a value that was already borrowed!"),
+        }
+    }
+
+    /// Get a pointer to the value, without borrowing it.
+    /// Dereferencing the pointer is only sound while the caller already holds a borrow of the value.
+    pub(super) fn as_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    /// Get the value without any borrow checking, since `&mut self` already guarantees exclusive access.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    /// Consume the BorrowCell, returning the value. No borrows can be alive, since `self` is taken by value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+struct BorrowRef<'b> {
+    borrow: &'b AtomicIsize,
+}
+
+impl<'b> BorrowRef<'b> {
+    fn new(borrow: &'b AtomicIsize) -> Option<BorrowRef<'b>> {
+        borrow
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, | flag | {
+                (flag >= UNUSED && flag != BorrowFlag::MAX).then_some(flag + 1)
+            })
+            .ok()
+            .map(| _ | BorrowRef { borrow })
+    }
+}
+
+impl Clone for BorrowRef<'_> {
+    fn clone(&self) -> Self {
+        // This borrow is already alive, so the flag can't be (or become) a mutable borrow
+        let flag = self.borrow.fetch_add(1, Ordering::Relaxed);
+        assert!(flag != BorrowFlag::MAX, "Too many immutable borrows!");
+        BorrowRef { borrow: self.borrow }
+    }
+}
+
+impl Drop for BorrowRef<'_> {
+    fn drop(&mut self) {
+        self.borrow.fetch_sub(1, Ordering::Release);
+    }
+}
+
+struct BorrowRefMut<'b> {
+    borrow: &'b AtomicIsize,
+}
+
+impl<'b> BorrowRefMut<'b> {
+    fn new(borrow: &'b AtomicIsize) -> Option<BorrowRefMut<'b>> {
+        borrow
+            .compare_exchange(UNUSED, UNUSED - 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(| _ | BorrowRefMut { borrow })
+    }
+
+    /// Share this mutable borrow with another RefMut.
+    /// Only sound if the two RefMuts point to disjoint parts of the value.
+    fn share(&self) -> BorrowRefMut<'b> {
+        // This borrow is already alive, so no other thread can change the flag
+        let flag = self.borrow.fetch_sub(1, Ordering::Relaxed);
+        assert!(flag != BorrowFlag::MIN, "Too many mutable borrows!");
+        BorrowRefMut { borrow: self.borrow }
+    }
+}
+
+impl Drop for BorrowRefMut<'_> {
+    fn drop(&mut self) {
+        self.borrow.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// An immutable borrow of (part of) the value in a BorrowCell.
+pub struct Ref<'b, T: ?Sized> {
+    value: NonNull<T>,
+    borrow: BorrowRef<'b>,
+}
+
+// SAFETY: a Ref behaves like `&T`, and its borrow flag is atomic
+unsafe impl<T: ?Sized + Sync> Send for Ref<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for Ref<'_, T> {}
+
+impl<'b, T: ?Sized> Ref<'b, T> {
+    /// Copy a Ref. Like `std::cell::Ref::clone`, this is an associated function
+    /// so that it doesn't get in the way of calling `clone` on the borrowed value.
+    #[allow(clippy::should_implement_trait)]
+    pub fn clone(orig: &Ref<'b, T>) -> Ref<'b, T> {
+        Ref {
+            value: orig.value,
+            borrow: orig.borrow.clone(),
+        }
+    }
+
+    /// Make a new Ref for a component of the borrowed data.
+    pub fn map<U: ?Sized, F>(orig: Ref<'b, T>, f: F) -> Ref<'b, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        Ref {
+            value: NonNull::from(f(&*orig)),
+            borrow: orig.borrow,
+        }
+    }
+
+    /// Make a new Ref for an optional component of the borrowed data.
+    /// The original Ref is returned if the closure returns None.
+    pub fn filter_map<U: ?Sized, F>(orig: Ref<'b, T>, f: F) -> Result<Ref<'b, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(&*orig) {
+            Some(value) => Ok(Ref {
+                value: NonNull::from(value),
+                borrow: orig.borrow,
+            }),
+            None => Err(orig),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the value is immutably borrowed for as long as this Ref is alive
+        unsafe { self.value.as_ref() }
+    }
+}
+
+/// A mutable borrow of (part of) the value in a BorrowCell.
+/// A RefMut to a component records a change to the component whenever it is mutably dereferenced
+/// (see `RefMut::set_changed_tick`).
+pub struct RefMut<'b, T: ?Sized> {
+    value: NonNull<T>,
+    borrow: BorrowRefMut<'b>,
+    /// Where to record that the value was changed, and the tick to record there.
+    changed_tick: Option<(NonNull<Tick>, Tick)>,
+    marker: PhantomData<&'b mut T>,
+}
+
+// SAFETY: a RefMut behaves like `&mut T`, and its borrow flag is atomic
+unsafe impl<T: ?Sized + Send> Send for RefMut<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for RefMut<'_, T> {}
+
+impl<'b, T: ?Sized> RefMut<'b, T> {
+    /// Make a new RefMut for a component of the borrowed data.
+    pub fn map<U: ?Sized, F>(mut orig: RefMut<'b, T>, f: F) -> RefMut<'b, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let value = NonNull::from(f(&mut *orig));
+        RefMut {
+            value,
+            borrow: orig.borrow,
+            changed_tick: orig.changed_tick,
+            marker: PhantomData,
+        }
+    }
+
+    /// Make a new RefMut for an optional component of the borrowed data.
+    /// The original RefMut is returned if the closure returns None.
+    pub fn filter_map<U: ?Sized, F>(mut orig: RefMut<'b, T>, f: F) -> Result<RefMut<'b, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        match f(&mut *orig).map(NonNull::from) {
+            Some(value) => Ok(RefMut {
+                value,
+                borrow: orig.borrow,
+                changed_tick: orig.changed_tick,
+                marker: PhantomData,
+            }),
+            None => Err(orig),
+        }
+    }
+
+    /// Make a new RefMut for a component of the borrowed data, sharing the original's borrow.
+    /// Unlike `map`, the original RefMut stays usable.
+    /// # Safety
+    /// `value` must point into the data borrowed by `orig`, and must not overlap with any other
+    /// RefMut sharing the same borrow. `orig` must not be mutably dereferenced while the new RefMut is alive.
+    pub(super) unsafe fn map_split_unchecked<U: ?Sized>(orig: &RefMut<'b, T>, value: NonNull<U>) -> RefMut<'b, U> {
+        RefMut {
+            value,
+            borrow: orig.borrow.share(),
+            changed_tick: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Record `tick` at `changed` whenever this RefMut is mutably dereferenced,
+    /// e.g., to update a component's ComponentTicks when the component is written to.
+    /// # Safety
+    /// `changed` must stay valid for as long as the RefMut is alive, and must not be accessed
+    /// through anything other than this RefMut while it is alive.
+    pub(super) unsafe fn set_changed_tick(
+        mut orig: RefMut<'b, T>,
+        changed: NonNull<Tick>,
+        tick: Tick,
+    ) -> RefMut<'b, T> {
+        orig.changed_tick = Some((changed, tick));
+        orig
+    }
+}
+
+impl<T: ?Sized> Deref for RefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the value is mutably borrowed for as long as this RefMut is alive
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: `changed` is only accessed through this RefMut (see `set_changed_tick`)
+        if let Some((changed, tick)) = self.changed_tick {
+            unsafe { *changed.as_ptr() = tick };
+        }
+        // SAFETY: the value is mutably borrowed for as long as this RefMut is alive
+        unsafe { self.value.as_mut() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "already mutably borrowed")]
+    fn borrow_while_mutably_borrowed_panics() {
+        let cell = BorrowCell::new(0);
+        let _ref_mut = cell.borrow_mut();
+        cell.borrow();
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn borrow_mut_while_borrowed_panics() {
+        let cell = BorrowCell::new(0);
+        let _ref = cell.borrow();
+        cell.borrow_mut();
+    }
+
+    #[test]
+    fn split_ref_muts_release_borrow_once_all_dropped() {
+        let cell = BorrowCell::new([1, 2]);
+        let mut orig = cell.borrow_mut();
+        let ptr = orig.as_mut_ptr();
+        // SAFETY: the two elements are disjoint, and `orig` isn't dereferenced while they're alive
+        let (mut first, mut second) = unsafe {
+            (
+                RefMut::map_split_unchecked(&orig, NonNull::new_unchecked(ptr)),
+                RefMut::map_split_unchecked(&orig, NonNull::new_unchecked(ptr.add(1))),
+            )
+        };
+        drop(orig);
+        *first += 10;
+        *second += 20;
+        assert_eq!(cell.borrow.load(Ordering::Relaxed), UNUSED - 2);
+        drop(first);
+        assert_eq!(cell.borrow.load(Ordering::Relaxed), UNUSED - 1);
+        drop(second);
+        assert_eq!(cell.borrow.load(Ordering::Relaxed), UNUSED);
+        assert_eq!(*cell.borrow(), [11, 22]);
+    }
+
+    #[test]
+    fn writes_through_ref_mut_stamp_changed_tick() {
+        let cell = BorrowCell::new(0);
+        let mut changed: Tick = 1;
+        let changed_ptr = NonNull::from(&mut changed);
+        // SAFETY: `changed` outlives the RefMut, and isn't accessed until it is dropped
+        let mut ref_mut = unsafe { RefMut::set_changed_tick(cell.borrow_mut(), changed_ptr, 5) };
+        assert_eq!(*ref_mut, 0);
+        // SAFETY: the RefMut is still alive, so `changed` can only be read through the pointer
+        assert_eq!(unsafe { *changed_ptr.as_ptr() }, 1, "Reading shouldn't count as a change");
+        *ref_mut = 3;
+        drop(ref_mut);
+        assert_eq!(changed, 5);
+        assert_eq!(*cell.borrow(), 3);
+    }
+}
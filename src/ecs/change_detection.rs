@@ -0,0 +1,50 @@
+/// A point in time in a World, used to tell when components were added and changed.
+/// The World's tick advances every time a system runs (see World::increment_change_tick).
+/// 64 bits are enough that the tick never wraps in practice.
+pub type Tick = u64;
+
+/// When a component was added to its Entity, and when it was last changed.
+/// Stored alongside every component.
+#[derive(Copy, Clone, Debug)]
+pub struct ComponentTicks {
+    #[allow(dead_code)]
+    pub added: Tick,
+    /// Adding a component also counts as changing it.
+    pub changed: Tick,
+}
+
+impl ComponentTicks {
+    pub fn new(tick: Tick) -> ComponentTicks {
+        ComponentTicks {
+            added: tick,
+            changed: tick,
+        }
+    }
+
+    /// Was the component added after `last_run`?
+    #[allow(dead_code)]
+    pub fn is_added(
+        &self,
+        last_run: Tick,
+    ) -> bool {
+        self.added > last_run
+    }
+
+    /// Was the component changed (or added) after `last_run`?
+    pub fn is_changed(
+        &self,
+        last_run: Tick,
+    ) -> bool {
+        self.changed > last_run
+    }
+}
+
+/// The ticks a Query (or system parameter) is fetched with.
+/// Changes are detected relative to `last_run`, and writes are recorded at `this_run`.
+#[derive(Copy, Clone, Debug)]
+pub struct SystemTicks {
+    /// The tick the system last ran at (0 if it has never run).
+    pub last_run: Tick,
+    /// The tick the system is running at now.
+    pub this_run: Tick,
+}
@@ -0,0 +1,143 @@
+use crate::bundle::Bundle;
+
+use super::{Component, Entity, EntityError, World};
+
+type Command = Box<dyn FnOnce(&mut World) + Send>;
+
+/// Stores structural changes to a World (e.g., creating Entities or adding components)
+/// so they can be applied later, once nothing in the World is borrowed.
+/// Record commands into the queue with Commands, then apply them at a sync point.
+pub struct CommandQueue {
+    commands: Vec<Command>,
+}
+
+impl CommandQueue {
+    pub fn new() -> CommandQueue {
+        CommandQueue {
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Apply every recorded command to the World, in the order they were recorded, emptying the queue.
+    /// Entities reserved by Commands::spawn are created first, so every command can refer to them.
+    /// Any commands recorded by component hooks (see World::on_add) are applied before this queue's commands,
+    /// and then straight after each command that triggers a hook, before the next command.
+    pub fn apply(
+        &mut self,
+        world: &mut World,
+    ) {
+        world.flush_entities();
+        world.apply_hook_commands();
+        for command in self.commands.drain(..) {
+            command(world);
+            world.apply_hook_commands();
+        }
+    }
+
+    fn push<F>(
+        &mut self,
+        command: F,
+    )
+    where F: FnOnce(&mut World) + Send + 'static {
+        self.commands.push(Box::new(command));
+    }
+}
+
+/// Records structural changes to a World into a CommandQueue, e.g., while iterating over a Query.
+/// Nothing happens to the World until the CommandQueue is applied.
+/// ```
+/// let mut command_queue = CommandQueue::new();
+/// let mut commands = Commands::new(&mut command_queue, &world);
+/// for (entity, _) in world.query::<With<Bullet>>() {
+///     commands.despawn(entity);
+/// }
+/// command_queue.apply(&mut world);
+/// ```
+pub struct Commands<'q, 'w> {
+    queue: &'q mut CommandQueue,
+    world: &'w World,
+}
+
+impl<'q, 'w> Commands<'q, 'w> {
+    pub fn new(
+        queue: &'q mut CommandQueue,
+        world: &'w World,
+    ) -> Commands<'q, 'w> {
+        Commands {
+            queue,
+            world,
+        }
+    }
+
+    /// Spawn a new Entity. Its ID is reserved immediately, so it can be used by later commands,
+    /// but the Entity won't be valid in the World until the CommandQueue is applied.
+    /// Fails if the World has run out of Entities (see World::reserve_entity).
+    pub fn spawn(&mut self) -> Result<Entity, EntityError> {
+        self.world.reserve_entity()
+    }
+
+    /// Destroy an Entity, along with all of its components.
+    pub fn despawn(
+        &mut self,
+        entity: Entity,
+    ) {
+        self.queue.push(move | world: &mut World | {
+            world.destroy_entity(entity);
+        });
+    }
+
+    /// Add a component to an Entity.
+    pub fn insert<T: Component>(
+        &mut self,
+        entity: Entity,
+        component: T,
+    ) {
+        self.queue.push(move | world: &mut World | {
+            // Won't error if the Entity was despawned by an earlier command, will just log
+            if let Err(error) = world.add_component(&entity, component) {
+                println!("Failed to apply a deferred component insert: {:?}", error);
+            }
+        });
+    }
+
+    /// Remove a component of type T from an Entity.
+    pub fn remove<T: Component>(
+        &mut self,
+        entity: Entity,
+    ) {
+        self.queue.push(move | world: &mut World | {
+            // Won't error if the Entity was despawned by an earlier command, will just log
+            if let Err(error) = world.remove_component::<T>(&entity) {
+                println!("Failed to apply a deferred component removal: {:?}", error);
+            }
+        });
+    }
+
+    /// Run a function with mutable access to the World, e.g., to read components before making changes.
+    pub fn add<F>(
+        &mut self,
+        command: F,
+    )
+    where F: FnOnce(&mut World) + Send + 'static {
+        self.queue.push(command);
+    }
+
+    /// Add every component in a Bundle to an Entity.
+    pub fn add_bundle<T>(
+        &mut self,
+        entity: Entity,
+        bundle: T,
+    )
+    where T: Bundle + Send + 'static {
+        self.queue.push(move | world: &mut World | {
+            // Won't error if the Entity was despawned by an earlier command, will just log
+            if let Err(error) = world.add_bundle(&entity, bundle) {
+                println!("Failed to apply a deferred bundle insert: {:?}", error);
+            }
+        });
+    }
+}
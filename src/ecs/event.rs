@@ -0,0 +1,119 @@
+use std::marker::PhantomData;
+
+use super::{Event, World};
+
+/// A channel of events of type T, stored in the World as a resource (see World::register_event).
+/// Events are double-buffered: each event is kept for the update it was sent in and the
+/// update after that, then dropped. Every EventCursor sees each event at most once,
+/// as long as it reads at least once every two updates.
+pub struct Events<T> {
+    /// Events sent before the most recent call to `update`.
+    previous: Vec<T>,
+    /// Events sent since the most recent call to `update`.
+    current: Vec<T>,
+    /// The ID of the first event in `previous`.
+    /// Every event sent gets the next ID, so the IDs of `previous` and `current` are contiguous.
+    start_id: usize,
+}
+
+impl<T> Events<T> {
+    pub fn new() -> Events<T> {
+        Events {
+            previous: Vec::new(),
+            current: Vec::new(),
+            start_id: 0,
+        }
+    }
+
+    pub fn send(
+        &mut self,
+        event: T,
+    ) {
+        self.current.push(event);
+    }
+
+    /// Swap the event buffers, dropping the events from two updates ago.
+    /// Called on every registered Events by World::update_events.
+    pub fn update(&mut self) {
+        self.start_id += self.previous.len();
+        self.previous.clear();
+        std::mem::swap(&mut self.previous, &mut self.current);
+    }
+}
+
+impl<T: Event> Events<T> {
+    /// Update the Events<T> resource in a World. Stored by World::register_event,
+    /// so that the World can update every Events without knowing their types.
+    pub(super) fn update_resource(world: &World) {
+        world
+            .get_resource_mut::<Events<T>>()
+            .expect("Registered event type is missing its Events resource!")
+            .update();
+    }
+}
+
+/// Keeps track of which events of type T have already been read.
+/// Each reader has its own cursor, so several readers can read the same events independently.
+/// Systems can use the EventReader parameter instead, which keeps a cursor for them.
+pub struct EventCursor<T> {
+    /// The ID of the next event this cursor will read.
+    next_id: usize,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> EventCursor<T> {
+    pub fn new() -> EventCursor<T> {
+        EventCursor {
+            next_id: 0,
+            marker: PhantomData,
+        }
+    }
+
+    /// Iterate over every event this cursor hasn't read yet (and that hasn't been dropped), oldest first.
+    /// An event only counts as read once the iterator has yielded it,
+    /// so stopping early leaves the rest of the events to be read next time.
+    pub fn read<'e>(
+        &mut self,
+        events: &'e Events<T>,
+    ) -> impl Iterator<Item = &'e T> {
+        // If events were dropped before this cursor got to them, skip straight to the oldest remaining event
+        self.next_id = self.next_id.max(events.start_id);
+        let skip = self.next_id - events.start_id;
+        events.previous
+            .iter()
+            .chain(events.current.iter())
+            .skip(skip)
+            .inspect(| _ | self.next_id += 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stopping_early_leaves_events_unread() {
+        let mut events = Events::new();
+        let mut cursor = EventCursor::new();
+        for event in 0..4 {
+            events.send(event);
+        }
+        assert_eq!(cursor.read(&events).take(1).collect::<Vec<_>>(), vec![&0]);
+        events.update();
+        events.send(4);
+        assert_eq!(cursor.read(&events).collect::<Vec<_>>(), vec![&1, &2, &3, &4]);
+        assert_eq!(cursor.read(&events).count(), 0);
+    }
+
+    #[test]
+    fn dropped_events_are_skipped() {
+        let mut events = Events::new();
+        let mut cursor = EventCursor::new();
+        events.send(0);
+        events.update();
+        events.send(1);
+        events.update();
+        events.send(2);
+        assert_eq!(cursor.read(&events).collect::<Vec<_>>(), vec![&1, &2]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{sync::{Condvar, Mutex, MutexGuard}, thread};
+
+use super::{thread_pool::ThreadPool, System, World};
+
+/// Which systems can be handed out to a thread, and how far through the stage the executor is.
+struct ExecutorState<'s> {
+    /// Each system, until it is handed out to a thread to run.
+    systems: Vec<Option<&'s mut Box<dyn System>>>,
+    /// `num_remaining_dependencies[i]` is the number of systems that must finish before system i can start.
+    num_remaining_dependencies: Vec<usize>,
+    /// Indices of the systems that are ready to run, lowest first.
+    ready: Vec<usize>,
+    num_finished: usize,
+    /// Has a system panicked? If so, every thread stops taking systems so the panic can propagate.
+    has_panicked: bool,
+}
+
+/// Runs a stage's systems on several threads at once.
+/// A system only starts once every system it depends on has finished, so systems that conflict
+/// (or are explicitly ordered) still run one after the other, in the same order as they would on a single thread.
+pub(super) struct ParallelExecutor<'s> {
+    state: Mutex<ExecutorState<'s>>,
+    /// Notified whenever a system finishes (or panics).
+    condvar: Condvar,
+    /// `dependents[i]` are the indices of the systems that depend on system i.
+    dependents: Vec<Vec<usize>>,
+    is_main_thread_only: Vec<bool>,
+}
+
+impl<'s> ParallelExecutor<'s> {
+    /// `dependencies[i]` are the indices of the systems that must finish before system i can start.
+    /// Every dependency must have a lower index than the system that depends on it.
+    pub(super) fn new(
+        systems: Vec<&'s mut Box<dyn System>>,
+        dependencies: &[Vec<usize>],
+    ) -> ParallelExecutor<'s> {
+        let mut dependents = vec![Vec::new(); systems.len()];
+        for (index, system_dependencies) in dependencies.iter().enumerate() {
+            for dependency in system_dependencies {
+                dependents[*dependency].push(index);
+            }
+        }
+        let num_remaining_dependencies: Vec<usize> = dependencies
+            .iter()
+            .map(| system_dependencies | system_dependencies.len())
+            .collect();
+        let ready = (0..systems.len())
+            .filter(| index | num_remaining_dependencies[*index] == 0)
+            .collect();
+        let is_main_thread_only = systems
+            .iter()
+            .map(| system | system.get_access().is_main_thread_only())
+            .collect();
+        ParallelExecutor {
+            state: Mutex::new(ExecutorState {
+                systems: systems.into_iter().map(Some).collect(),
+                num_remaining_dependencies,
+                ready,
+                num_finished: 0,
+                has_panicked: false,
+            }),
+            condvar: Condvar::new(),
+            dependents,
+            is_main_thread_only,
+        }
+    }
+
+    /// Run every system on the calling thread and `num_workers` of the ThreadPool's workers,
+    /// returning once they have all finished.
+    /// Main-thread-only systems are always run on the calling thread.
+    /// If a system panics, the panic is propagated to the caller once every running system has finished.
+    pub(super) fn run(
+        &self,
+        world: &World,
+        thread_pool: &ThreadPool,
+        num_workers: usize,
+    ) {
+        thread_pool.run(
+            num_workers,
+            &| | self.run_systems(world, false),
+            | | self.run_systems(world, true),
+        );
+    }
+
+    /// Take ready systems and run them until every system has finished.
+    fn run_systems(
+        &self,
+        world: &World,
+        is_main_thread: bool,
+    ) {
+        let _guard = PanicGuard { executor: self };
+        while let Some((index, system)) = self.take_ready_system(is_main_thread) {
+            system.run(world);
+            self.finish_system(index);
+        }
+    }
+
+    /// Wait until a system this thread can run is ready, then take it.
+    /// Returns None once every system has finished (or a system has panicked).
+    fn take_ready_system(
+        &self,
+        is_main_thread: bool,
+    ) -> Option<(usize, &'s mut Box<dyn System>)> {
+        let mut state = self.lock_state();
+        loop {
+            if state.has_panicked || state.num_finished == state.systems.len() {
+                return None
+            }
+            // The main thread takes main-thread-only systems first, as no other thread can run them
+            let position = if is_main_thread {
+                state.ready
+                    .iter()
+                    .position(| index | self.is_main_thread_only[*index])
+                    .or_else(| | (!state.ready.is_empty()).then_some(0))
+            } else {
+                state.ready.iter().position(| index | !self.is_main_thread_only[*index])
+            };
+            if let Some(position) = position {
+                let index = state.ready.remove(position);
+                let system = state.systems[index].take().unwrap();
+                return Some((index, system))
+            }
+            state = self.condvar.wait(state).unwrap_or_else(| error | error.into_inner());
+        }
+    }
+
+    /// Mark a system as finished, readying any systems that were only waiting on it.
+    fn finish_system(
+        &self,
+        index: usize,
+    ) {
+        let mut state = self.lock_state();
+        state.num_finished += 1;
+        for dependent in &self.dependents[index] {
+            state.num_remaining_dependencies[*dependent] -= 1;
+            if state.num_remaining_dependencies[*dependent] == 0 {
+                let position = state.ready.partition_point(| ready_index | ready_index < dependent);
+                state.ready.insert(position, *dependent);
+            }
+        }
+        self.condvar.notify_all();
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, ExecutorState<'s>> {
+        // The state is never left inconsistent while locked, so it is still usable if another thread panicked
+        self.state.lock().unwrap_or_else(| error | error.into_inner())
+    }
+}
+
+/// Stops every thread from taking more systems if the thread holding it panics,
+/// so that the threads can be joined and the panic propagated.
+struct PanicGuard<'e, 's> {
+    executor: &'e ParallelExecutor<'s>,
+}
+
+impl Drop for PanicGuard<'_, '_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.executor.lock_state().has_panicked = true;
+            self.executor.condvar.notify_all();
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use super::{Commands, Entity, World};
+
+#[derive(Debug)]
+pub enum HierarchyError {
+    /// An Entity was invalid (either dead or its generation was outdated).
+    InvalidEntity,
+    /// The parent was the child itself, or one of its descendants.
+    Cycle,
+}
+
+/// The Entity this Entity is a child of. Always mirrored by the parent's Children component.
+/// Can't be added or removed directly, to keep the two consistent: use World::set_parent and World::remove_parent instead.
+#[derive(Debug)]
+pub struct Parent {
+    parent: Entity,
+}
+
+impl Parent {
+    pub fn get(&self) -> Entity {
+        self.parent
+    }
+}
+
+/// The Entities that are children of this Entity, in the order they were parented.
+/// Always mirrored by each child's Parent component. Removed once the last child is, and can't be removed directly.
+#[derive(Debug)]
+pub struct Children {
+    children: Vec<Entity>,
+}
+
+impl Children {
+    pub fn iter(&self) -> impl Iterator<Item = &Entity> {
+        self.children.iter()
+    }
+}
+
+impl World {
+    /// Make `child` a child of `parent`, removing it from its previous parent's Children (if it had one).
+    /// Fails (leaving the hierarchy unchanged) if `parent` is `child` itself or one of its descendants,
+    /// so the hierarchy can never contain a cycle.
+    pub fn set_parent(
+        &mut self,
+        child: Entity,
+        parent: Entity,
+    ) -> Result<(), HierarchyError> {
+        if !self.entity_allocator.is_valid(&child) || !self.entity_allocator.is_valid(&parent) {
+            return Err(HierarchyError::InvalidEntity)
+        }
+        let mut ancestor = Some(parent);
+        while let Some(ancestor_entity) = ancestor {
+            if ancestor_entity == child {
+                return Err(HierarchyError::Cycle)
+            }
+            ancestor = self.get_parent(&ancestor_entity);
+        }
+
+        self.remove_parent(child)?;
+        self.add_component(&child, Parent { parent }).expect("Parent component missing from the world!");
+        if let Some(mut children) = self.get_component_mut::<Children>(&parent).expect("Children component missing from the world!") {
+            children.children.push(child);
+        } else {
+            self.add_component(&parent, Children { children: vec![child] }).expect("Children component missing from the world!");
+        }
+        Ok(())
+    }
+
+    /// Remove `child` from its parent's Children, making it a root. Does nothing if it has no parent.
+    pub fn remove_parent(
+        &mut self,
+        child: Entity,
+    ) -> Result<(), HierarchyError> {
+        if !self.entity_allocator.is_valid(&child) {
+            return Err(HierarchyError::InvalidEntity)
+        }
+        let Some(parent) = self.get_parent(&child) else {
+            return Ok(())
+        };
+        self.remove_any_component::<Parent>(&child).expect("Parent component missing from the world!");
+        self.remove_child(&parent, &child);
+        Ok(())
+    }
+
+    fn get_parent(
+        &self,
+        child: &Entity,
+    ) -> Option<Entity> {
+        self.get_component::<Parent>(child)
+            .expect("Parent component missing from the world!")
+            .map(| parent | parent.get())
+    }
+
+    /// Remove `child` from `parent`'s Children, removing the component if it was the last child.
+    fn remove_child(
+        &self,
+        parent: &Entity,
+        child: &Entity,
+    ) {
+        let Some(mut children) = self.get_component_mut::<Children>(parent).expect("Children component missing from the world!") else {
+            return
+        };
+        children.children.retain(| entity | entity != child);
+        if children.children.is_empty() {
+            drop(children);
+            self.remove_any_component::<Children>(parent).expect("Children component missing from the world!");
+        }
+    }
+
+    /// Destroy an Entity along with all of its descendants, deepest first.
+    /// Its parent (if it has one) loses it from its Children, as with `destroy_entity`.
+    pub fn despawn_recursive(
+        &mut self,
+        entity: Entity,
+    ) {
+        let mut subtree = vec![entity];
+        let mut next = 0;
+        while let Some(subtree_entity) = subtree.get(next) {
+            if let Ok(Some(children)) = self.get_component::<Children>(subtree_entity) {
+                subtree.extend(children.iter());
+            }
+            next += 1;
+        }
+        // Destroying children before their parents means none of them are ever detached into roots, even briefly
+        for subtree_entity in subtree.into_iter().rev() {
+            self.destroy_entity(subtree_entity);
+        }
+    }
+
+    /// Detach an Entity that is about to be destroyed from the hierarchy:
+    /// it is removed from its parent's Children, and its children become roots.
+    pub(super) fn detach_from_hierarchy(
+        &mut self,
+        entity: &Entity,
+    ) {
+        if let Some(parent) = self.get_parent(entity) {
+            self.remove_child(&parent, entity);
+        }
+        let children = self.get_component::<Children>(entity)
+            .expect("Children component missing from the world!")
+            .map(| children | children.children.clone())
+            .unwrap_or_default();
+        for child in children {
+            self.remove_any_component::<Parent>(&child).expect("Parent component missing from the world!");
+        }
+    }
+}
+
+impl Commands<'_, '_> {
+    /// Make `child` a child of `parent`. See World::set_parent.
+    pub fn set_parent(
+        &mut self,
+        child: Entity,
+        parent: Entity,
+    ) {
+        self.add(move | world: &mut World | {
+            // Won't error if either Entity was despawned by an earlier command, will just log
+            if let Err(error) = world.set_parent(child, parent) {
+                println!("Failed to apply a deferred set parent: {:?}", error);
+            }
+        });
+    }
+
+    /// Destroy an Entity along with all of its descendants. See World::despawn_recursive.
+    pub fn despawn_recursive(
+        &mut self,
+        entity: Entity,
+    ) {
+        self.add(move | world: &mut World | {
+            world.despawn_recursive(entity);
+        });
+    }
+
+    /// Make `child` a root. See World::remove_parent.
+    pub fn remove_parent(
+        &mut self,
+        child: Entity,
+    ) {
+        self.add(move | world: &mut World | {
+            // Won't error if the Entity was despawned by an earlier command, will just log
+            if let Err(error) = world.remove_parent(child) {
+                println!("Failed to apply a deferred parent removal: {:?}", error);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Children, HierarchyError, Parent};
+    use crate::ecs::{EntityComponentError, World};
+
+    #[test]
+    fn removing_hierarchy_components_directly_fails() {
+        let mut world = World::new();
+        let parent = world.create_entity().unwrap();
+        let child = world.create_entity().unwrap();
+        world.set_parent(child, parent).unwrap();
+        assert!(matches!(world.remove_component::<Parent>(&child), Err(EntityComponentError::HierarchyComponent)));
+        assert!(matches!(world.remove_component::<Children>(&parent), Err(EntityComponentError::HierarchyComponent)));
+        assert_eq!(world.get_parent(&child), Some(parent));
+        let children = world.get_component::<Children>(&parent).unwrap().unwrap().iter().copied().collect::<Vec<_>>();
+        assert_eq!(children, vec![child]);
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let mut world = World::new();
+        let grandparent = world.create_entity().unwrap();
+        let parent = world.create_entity().unwrap();
+        let child = world.create_entity().unwrap();
+        world.set_parent(parent, grandparent).unwrap();
+        world.set_parent(child, parent).unwrap();
+        assert!(matches!(world.set_parent(grandparent, child), Err(HierarchyError::Cycle)));
+        assert!(matches!(world.set_parent(child, child), Err(HierarchyError::Cycle)));
+        assert_eq!(world.get_parent(&grandparent), None);
+        assert_eq!(world.get_parent(&child), Some(parent));
+    }
+
+    #[test]
+    fn despawn_recursive_destroys_whole_subtree() {
+        let mut world = World::new();
+        let root = world.create_entity().unwrap();
+        let entity = world.create_entity().unwrap();
+        let child = world.create_entity().unwrap();
+        let grandchild = world.create_entity().unwrap();
+        let sibling = world.create_entity().unwrap();
+        world.set_parent(entity, root).unwrap();
+        world.set_parent(sibling, root).unwrap();
+        world.set_parent(child, entity).unwrap();
+        world.set_parent(grandchild, child).unwrap();
+        world.despawn_recursive(entity);
+        for destroyed in [entity, child, grandchild] {
+            assert!(!world.entity_allocator.is_valid(&destroyed));
+        }
+        assert_eq!(world.query::<&Parent>().count(), 1);
+        let children = world.get_component::<Children>(&root).unwrap().unwrap().iter().copied().collect::<Vec<_>>();
+        assert_eq!(children, vec![sibling]);
+    }
+}
@@ -0,0 +1,50 @@
+use super::{Commands, Entity};
+
+/// A callback run when a component is added to, replaced on, or removed from an Entity.
+/// The World may be borrowed when a hook runs, so hooks can't access it directly.
+/// Instead, they record changes with Commands (see World::on_add for when those are applied).
+pub type ComponentHook = Box<dyn Fn(Entity, &mut Commands) + Send + Sync>;
+
+/// The lifecycle events a ComponentHook can be registered for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum HookKind {
+    /// The component was added to an Entity that didn't have one.
+    Add,
+    /// The component was added to an Entity that already had one, replacing it.
+    Replace,
+    /// The component was removed from an Entity, or the Entity was destroyed while holding it.
+    Remove,
+}
+
+/// The hooks registered for a single component type, run in the order they were registered.
+#[derive(Default)]
+pub(super) struct ComponentHooks {
+    on_add: Vec<ComponentHook>,
+    on_replace: Vec<ComponentHook>,
+    on_remove: Vec<ComponentHook>,
+}
+
+impl ComponentHooks {
+    pub fn get(
+        &self,
+        hook_kind: HookKind,
+    ) -> &[ComponentHook] {
+        match hook_kind {
+            HookKind::Add => &self.on_add,
+            HookKind::Replace => &self.on_replace,
+            HookKind::Remove => &self.on_remove,
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        hook_kind: HookKind,
+        hook: ComponentHook,
+    ) {
+        match hook_kind {
+            HookKind::Add => self.on_add.push(hook),
+            HookKind::Replace => self.on_replace.push(hook),
+            HookKind::Remove => self.on_remove.push(hook),
+        }
+    }
+}
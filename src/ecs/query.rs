@@ -0,0 +1,1111 @@
+use std::{any::TypeId, marker::PhantomData, ops::Range, ptr::NonNull, sync::{atomic::{AtomicUsize, Ordering}, Mutex, TryLockError}, thread};
+
+use super::{
+    archetype::{Archetype, ArchetypeId, Archetypes, ColumnPtrs, ComponentColumns, EntityLocation},
+    cell::{BorrowCell, Ref, RefMut},
+    system::SystemAccess,
+    thread_pool::ThreadPool,
+    Component, ComponentPool, ComponentStorage, ComponentTicks, Entity, StorageType, SystemTicks, Tick, World,
+};
+
+/// Used to create Query trait objects. When used with World::query,
+/// gets all Entities that match the given Query.
+/// Components in the Query don't need to be registered: no Entity has an unregistered component,
+/// so e.g., `&T` matches nothing and `Without<T>` matches everything.
+pub trait Query {
+    /// The result of this Query for a single matching Entity.
+    type Item<'w>;
+    /// The storage this Query borrows for as long as it is being iterated over.
+    type Fetch<'w>;
+
+    /// Record which component types this Query reads and writes. See System::get_access.
+    fn add_access(access: &mut SystemAccess);
+    /// Does this Query borrow the component type mutably?
+    fn writes_component(_type_id: &TypeId) -> bool {
+        false
+    }
+    /// Borrow the storage needed to iterate over this Query.
+    /// Each component's storage is only borrowed once, here, rather than once per Entity.
+    /// `ticks` are used to detect changes (see Added and Changed) and to record them (see `&mut A`).
+    /// `query_writes` is `writes_component` of the whole Query this term is part of,
+    /// so terms can read through storage another term borrows mutably, rather than borrowing it again (see TicksFetch).
+    fn init_fetch(world: &World, ticks: SystemTicks, query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_>;
+    /// Get the Entities of the smallest ComponentPool that every match is required to have a component from.
+    /// QueryIter walks this pool's dense array, unless walking the archetypes would be shorter.
+    fn get_smallest_pool<'f>(fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]>;
+    /// Can Entities in this archetype match the Query?
+    /// Lets QueryIter skip whole archetypes based on their Table components.
+    fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool;
+    /// Fetch the result of the Query for this Entity, if it matches.
+    /// Takes the fetch by shared reference, so that several threads can fetch from it at once (see QueryIter::par_for_each).
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        location: EntityLocation,
+        archetype: &Archetype,
+    ) -> Option<Self::Item<'w>>;
+}
+
+/// The storage of a component type, borrowed immutably for as long as a Query is being iterated over.
+pub struct ComponentFetch<'w, T> {
+    storage: StorageRef<'w, T>,
+}
+
+enum StorageRef<'w, T> {
+    Pool(Ref<'w, ComponentPool<T>>),
+    Columns(Ref<'w, ComponentColumns<T>>),
+    /// T isn't registered, so no Entity has one.
+    Unregistered,
+}
+
+impl<T: Component> ComponentFetch<'_, T> {
+    fn new(world: &World) -> ComponentFetch<'_, T> {
+        let storage = match world.get_storage_type::<T>() {
+            Ok(StorageType::SparseSet) => StorageRef::Pool(world.get_component_pool::<T>().unwrap()),
+            Ok(StorageType::Table) => StorageRef::Columns(world.get_component_columns::<T>().unwrap()),
+            Err(_) => StorageRef::Unregistered,
+        };
+        ComponentFetch { storage }
+    }
+}
+
+/// The storage of a component type, borrowed mutably for as long as a Query is being iterated over.
+pub struct ComponentFetchMut<'w, T> {
+    storage: StorageRefMut<'w, T>,
+    /// The tick to record when a fetched component is written to.
+    this_run: Tick,
+}
+
+/// The pointers in each variant are taken once, when the storage is borrowed, so that fetching a component
+/// (possibly on several threads at once, see QueryIter::par_for_each) never needs `&mut` access to the storage.
+enum StorageRefMut<'w, T> {
+    Pool {
+        pool: RefMut<'w, ComponentPool<T>>,
+        /// The start of the pool's `components`.
+        components: NonNull<T>,
+        /// The start of the pool's `ticks`.
+        ticks: NonNull<ComponentTicks>,
+    },
+    Columns {
+        columns: RefMut<'w, ComponentColumns<T>>,
+        /// See ComponentColumns::get_column_ptrs.
+        column_ptrs: NonNull<[ColumnPtrs<T>]>,
+    },
+    /// T isn't registered, so no Entity has one.
+    Unregistered,
+}
+
+// SAFETY: the pointers only point into the borrowed storage, so can be shared like the RefMuts themselves
+unsafe impl<T: Send> Send for StorageRefMut<'_, T> {}
+unsafe impl<T: Sync> Sync for StorageRefMut<'_, T> {}
+
+impl<T: Component> ComponentFetchMut<'_, T> {
+    fn new(
+        world: &World,
+        this_run: Tick,
+    ) -> ComponentFetchMut<'_, T> {
+        let storage = match world.get_storage_type::<T>() {
+            Ok(StorageType::SparseSet) => {
+                let mut pool = world.get_component_pool_mut::<T>().unwrap();
+                // SAFETY: a Vec's pointer is never null, even when it hasn't allocated
+                let (components, ticks) = unsafe {(
+                    NonNull::new_unchecked(pool.components.as_mut_ptr()),
+                    NonNull::new_unchecked(pool.ticks.as_mut_ptr()),
+                )};
+                StorageRefMut::Pool { pool, components, ticks }
+            },
+            Ok(StorageType::Table) => {
+                let mut columns = world.get_component_columns_mut::<T>().unwrap();
+                let column_ptrs = NonNull::from(columns.get_column_ptrs());
+                StorageRefMut::Columns { columns, column_ptrs }
+            },
+            Err(_) => StorageRefMut::Unregistered,
+        };
+        ComponentFetchMut { storage, this_run }
+    }
+}
+
+/// Get all Entities that have the component A, borrowing A immutably.
+/// # Examples:
+/// ```
+/// world.query::<(&Transform)>()
+///     .iter()
+///     .for_each(| entity, transform | {
+///         ...
+///     })
+/// ```
+impl <A: Component> Query for &A {
+    type Item<'w> = Ref<'w, A>;
+    type Fetch<'w> = ComponentFetch<'w, A>;
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_component_read::<A>();
+    }
+
+    fn init_fetch(world: &World, _ticks: SystemTicks, _query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+        ComponentFetch::new(world)
+    }
+
+    fn get_smallest_pool<'f>(fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+        match &fetch.storage {
+            StorageRef::Pool(pool) => Some(&pool.entities_with_component),
+            StorageRef::Columns(_) => None,
+            // Nothing matches, so drive the Query with an empty pool
+            StorageRef::Unregistered => Some(&[]),
+        }
+    }
+
+    fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool {
+        match &fetch.storage {
+            StorageRef::Pool(_) => true,
+            StorageRef::Columns(_) => archetype.has_component_type(&TypeId::of::<A>()),
+            StorageRef::Unregistered => false,
+        }
+    }
+
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        location: EntityLocation,
+        _archetype: &Archetype,
+    ) -> Option<Self::Item<'w>> {
+        match &fetch.storage {
+            StorageRef::Pool(pool) => {
+                let dense_data_index = pool.get_dense_index(entity)?;
+                Some(Ref::map(Ref::clone(pool), | pool_ref | {
+                    &pool_ref.components[dense_data_index]
+                }))
+            },
+            StorageRef::Columns(columns) => {
+                Ref::filter_map(Ref::clone(columns), | columns_ref | {
+                    columns_ref.get(location)
+                }).ok()
+            },
+            StorageRef::Unregistered => None,
+        }
+    }
+}
+
+/// Get all Entities that have the component A, borrowing A mutably.
+/// # Examples:
+/// ```
+/// world.query::<(&mut Transform)>()
+///     .iter()
+///     .for_each(| entity, mut transform | {
+///         ...
+///     })
+/// ```
+impl <A: Component> Query for &mut A {
+    type Item<'w> = RefMut<'w, A>;
+    type Fetch<'w> = ComponentFetchMut<'w, A>;
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_component_write::<A>();
+    }
+
+    fn writes_component(type_id: &TypeId) -> bool {
+        *type_id == TypeId::of::<A>()
+    }
+
+    fn init_fetch(world: &World, ticks: SystemTicks, _query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+        ComponentFetchMut::new(world, ticks.this_run)
+    }
+
+    fn get_smallest_pool<'f>(fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+        match &fetch.storage {
+            StorageRefMut::Pool { pool, .. } => Some(&pool.entities_with_component),
+            StorageRefMut::Columns { .. } => None,
+            // Nothing matches, so drive the Query with an empty pool
+            StorageRefMut::Unregistered => Some(&[]),
+        }
+    }
+
+    fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool {
+        match &fetch.storage {
+            StorageRefMut::Pool { .. } => true,
+            StorageRefMut::Columns { .. } => archetype.has_component_type(&TypeId::of::<A>()),
+            StorageRefMut::Unregistered => false,
+        }
+    }
+
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        location: EntityLocation,
+        _archetype: &Archetype,
+    ) -> Option<Self::Item<'w>> {
+        // The storage stays mutably borrowed by the fetch, and each yielded RefMut shares that borrow.
+        // SAFETY: QueryIter visits each Entity at most once (as does QueryIter::par_for_each, across all of its threads),
+        // so no two RefMuts point to the same component (or the same ComponentTicks).
+        // Only pointer arithmetic is done on the pointers taken when the storage was borrowed,
+        // and the storage itself is only ever immutably dereferenced (to find a pool's dense index),
+        // so no `&mut` to the storage is created while the Query is being iterated over.
+        let (component, ticks) = match &fetch.storage {
+            StorageRefMut::Pool { pool, components, ticks } => {
+                let dense_data_index = pool.get_dense_index(entity)?;
+                let (component, ticks) = unsafe {(
+                    components.add(dense_data_index),
+                    ticks.add(dense_data_index),
+                )};
+                (unsafe { RefMut::map_split_unchecked(pool, component) }, ticks)
+            },
+            StorageRefMut::Columns { columns, column_ptrs } => {
+                let column = unsafe { column_ptrs.as_ref() }.get(location.archetype)?;
+                if location.row >= column.len {
+                    return None
+                }
+                let (component, ticks) = unsafe {(
+                    column.components.add(location.row),
+                    column.ticks.add(location.row),
+                )};
+                (unsafe { RefMut::map_split_unchecked(columns, component) }, ticks)
+            },
+            StorageRefMut::Unregistered => return None,
+        };
+        // Writing to the component through the RefMut records the change
+        let changed = unsafe { NonNull::new_unchecked(&raw mut (*ticks.as_ptr()).changed) };
+        Some(unsafe { RefMut::set_changed_tick(component, changed, fetch.this_run) })
+    }
+}
+
+/// Query filter matching Entities that have the component T.
+/// Unlike `&T`, the component itself is not fetched, so the result of this term is `()`.
+/// # Examples:
+/// ```
+/// world.query::<(With<Player>, &mut Transform)>()
+///     .for_each(| entity, (_, mut transform) | {
+///         ...
+///     })
+/// ```
+pub struct With<T>(PhantomData<T>);
+
+/// Query filter matching Entities that do not have the component T.
+/// The result of this term is `()`.
+/// # Examples:
+/// ```
+/// world.query::<(&Transform, With<Collider>, Without<Player>)>()
+///     .for_each(| entity, (transform, _, _) | {
+///         ...
+///     })
+/// ```
+pub struct Without<T>(PhantomData<T>);
+
+/// The storage used by With and Without filters.
+/// For Table components, the Entity's archetype is enough to know whether it has the component,
+/// so only ComponentPools need to be borrowed.
+/// Unregistered components are treated like Table components: no archetype has them, so no Entity does either.
+pub struct FilterFetch<'w, T> {
+    pool: Option<Ref<'w, ComponentPool<T>>>,
+}
+
+impl<T: Component> FilterFetch<'_, T> {
+    fn new(world: &World) -> FilterFetch<'_, T> {
+        let pool = match world.get_storage_type::<T>() {
+            Ok(StorageType::SparseSet) => Some(world.get_component_pool::<T>().unwrap()),
+            Ok(StorageType::Table) | Err(_) => None,
+        };
+        FilterFetch { pool }
+    }
+
+    fn has_component(
+        &self,
+        entity: &Entity,
+        archetype: &Archetype,
+    ) -> bool {
+        match &self.pool {
+            Some(pool) => pool.get_dense_index(entity).is_some(),
+            None => archetype.has_component_type(&TypeId::of::<T>()),
+        }
+    }
+}
+
+impl <T: Component> Query for With<T> {
+    type Item<'w> = ();
+    type Fetch<'w> = FilterFetch<'w, T>;
+
+    fn add_access(access: &mut SystemAccess) {
+        // Filters don't fetch T, but may still borrow its ComponentPool to check for it
+        access.add_component_read::<T>();
+    }
+
+    fn init_fetch(world: &World, _ticks: SystemTicks, _query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+        FilterFetch::new(world)
+    }
+
+    fn get_smallest_pool<'f>(fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+        // Every match must have a T, so T's pool can still drive the Query
+        fetch.pool
+            .as_ref()
+            .map(| pool | &pool.entities_with_component[..])
+    }
+
+    fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool {
+        fetch.pool.is_some() || archetype.has_component_type(&TypeId::of::<T>())
+    }
+
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        _location: EntityLocation,
+        archetype: &Archetype,
+    ) -> Option<Self::Item<'w>> {
+        fetch.has_component(entity, archetype).then_some(())
+    }
+}
+
+impl <T: Component> Query for Without<T> {
+    type Item<'w> = ();
+    type Fetch<'w> = FilterFetch<'w, T>;
+
+    fn add_access(access: &mut SystemAccess) {
+        // Filters don't fetch T, but may still borrow its ComponentPool to check for it
+        access.add_component_read::<T>();
+    }
+
+    fn init_fetch(world: &World, _ticks: SystemTicks, _query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+        FilterFetch::new(world)
+    }
+
+    fn get_smallest_pool<'f>(_fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+        // Matches don't share a pool, so this term can't drive the Query
+        None
+    }
+
+    fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool {
+        fetch.pool.is_some() || !archetype.has_component_type(&TypeId::of::<T>())
+    }
+
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        _location: EntityLocation,
+        archetype: &Archetype,
+    ) -> Option<Self::Item<'w>> {
+        (!fetch.has_component(entity, archetype)).then_some(())
+    }
+}
+
+/// Query filter matching Entities whose component T was added since the system last ran.
+/// The result of this term is `()`. Can be paired with either `&T` or `&mut T`.
+/// # Examples:
+/// ```
+/// fn setup_collider_system(added: QueryParam<(&Collider, Added<Collider>)>) {
+///     for (entity, (collider, _)) in &added {
+///         ...
+///     }
+/// }
+/// ```
+#[allow(dead_code)]
+pub struct Added<T>(PhantomData<T>);
+
+/// Query filter matching Entities whose component T was changed (or added) since the system last ran.
+/// A component counts as changed whenever it is mutably dereferenced, whether or not its value actually changed.
+/// The result of this term is `()`. Can be paired with either `&T` or `&mut T`.
+/// # Examples:
+/// ```
+/// fn moved_system(moved: QueryParam<(&mut Transform, Changed<Transform>)>) {
+///     for (entity, (mut transform, _)) in &moved {
+///         ...
+///     }
+/// }
+/// ```
+pub struct Changed<T>(PhantomData<T>);
+
+/// The storage used by Added and Changed filters.
+/// Unlike FilterFetch, Table components need their columns borrowed too, to read their ticks.
+pub struct TicksFetch<'w, T> {
+    storage: TicksStorage<'w, T>,
+    /// Changes are detected relative to this tick.
+    last_run: Tick,
+}
+
+enum TicksStorage<'w, T> {
+    /// T's storage, borrowed immutably by this term.
+    Borrowed(StorageRef<'w, T>),
+    /// Another term of the same Query borrows T's storage mutably (e.g., `(&mut T, Changed<T>)`),
+    /// so it can't be borrowed again. The ticks are read through that term's borrow instead.
+    Shared {
+        cell: &'w BorrowCell<Box<dyn ComponentStorage>>,
+        storage_type: StorageType,
+    },
+}
+
+impl<T: Component> TicksFetch<'_, T> {
+    fn new(
+        world: &World,
+        last_run: Tick,
+        query_writes: fn(&TypeId) -> bool,
+    ) -> TicksFetch<'_, T> {
+        let storage = match world.get_storage_type::<T>() {
+            Ok(storage_type) if query_writes(&TypeId::of::<T>()) => TicksStorage::Shared {
+                cell: world.get_component_storage_cell::<T>().unwrap(),
+                storage_type,
+            },
+            _ => TicksStorage::Borrowed(ComponentFetch::<T>::new(world).storage),
+        };
+        TicksFetch { storage, last_run }
+    }
+
+    fn get_smallest_pool(&self) -> Option<&[Entity]> {
+        // Every match must have a T, so T's pool can still drive the Query
+        match &self.storage {
+            TicksStorage::Borrowed(StorageRef::Pool(pool)) => Some(&pool.entities_with_component),
+            TicksStorage::Borrowed(StorageRef::Columns(_)) => None,
+            TicksStorage::Borrowed(StorageRef::Unregistered) => Some(&[]),
+            // The term borrowing T mutably can drive the Query with T's pool instead
+            TicksStorage::Shared { .. } => None,
+        }
+    }
+
+    fn matches_archetype(
+        &self,
+        archetype: &Archetype,
+    ) -> bool {
+        match &self.storage {
+            TicksStorage::Borrowed(StorageRef::Pool(_)) | TicksStorage::Shared { storage_type: StorageType::SparseSet, .. } => true,
+            TicksStorage::Borrowed(StorageRef::Columns(_)) | TicksStorage::Shared { storage_type: StorageType::Table, .. } => {
+                archetype.has_component_type(&TypeId::of::<T>())
+            },
+            TicksStorage::Borrowed(StorageRef::Unregistered) => false,
+        }
+    }
+
+    fn get_ticks(
+        &self,
+        entity: &Entity,
+        location: EntityLocation,
+    ) -> Option<ComponentTicks> {
+        match &self.storage {
+            TicksStorage::Borrowed(StorageRef::Pool(pool)) => pool.read_ticks(entity),
+            TicksStorage::Borrowed(StorageRef::Columns(columns)) => columns.read_ticks(location),
+            TicksStorage::Borrowed(StorageRef::Unregistered) => None,
+            TicksStorage::Shared { cell, storage_type } => {
+                // SAFETY: the storage is mutably borrowed by another term of this Query for as long as the fetch is alive,
+                // and that term never creates a `&mut` to the storage while the Query is being iterated over (see `&mut A`).
+                // The ticks are copied out without creating a reference into them, since the RefMuts handed out by
+                // that term may be writing to other components' ticks at the same time
+                let storage = unsafe { &**cell.as_ptr() };
+                match storage_type {
+                    StorageType::SparseSet => storage.as_any().downcast_ref::<ComponentPool<T>>()?.read_ticks(entity),
+                    StorageType::Table => storage.as_any().downcast_ref::<ComponentColumns<T>>()?.read_ticks(location),
+                }
+            },
+        }
+    }
+}
+
+impl <T: Component> Query for Added<T> {
+    type Item<'w> = ();
+    type Fetch<'w> = TicksFetch<'w, T>;
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_component_read::<T>();
+    }
+
+    fn init_fetch(world: &World, ticks: SystemTicks, query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+        TicksFetch::new(world, ticks.last_run, query_writes)
+    }
+
+    fn get_smallest_pool<'f>(fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+        fetch.get_smallest_pool()
+    }
+
+    fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool {
+        fetch.matches_archetype(archetype)
+    }
+
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        location: EntityLocation,
+        _archetype: &Archetype,
+    ) -> Option<Self::Item<'w>> {
+        fetch.get_ticks(entity, location)?.is_added(fetch.last_run).then_some(())
+    }
+}
+
+impl <T: Component> Query for Changed<T> {
+    type Item<'w> = ();
+    type Fetch<'w> = TicksFetch<'w, T>;
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_component_read::<T>();
+    }
+
+    fn init_fetch(world: &World, ticks: SystemTicks, query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+        TicksFetch::new(world, ticks.last_run, query_writes)
+    }
+
+    fn get_smallest_pool<'f>(fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+        fetch.get_smallest_pool()
+    }
+
+    fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool {
+        fetch.matches_archetype(archetype)
+    }
+
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        location: EntityLocation,
+        _archetype: &Archetype,
+    ) -> Option<Self::Item<'w>> {
+        fetch.get_ticks(entity, location)?.is_changed(fetch.last_run).then_some(())
+    }
+}
+
+/// Get all Entities, along with the result of the Query Q if the Entity matches it.
+/// This term never excludes an Entity, so it also never drives the Query.
+/// # Examples:
+/// ```
+/// world.query::<(&Transform, Option<&ChildOf>)>()
+///     .for_each(| entity, (transform, maybe_child_of) | {
+///         ...
+///     })
+/// ```
+impl <Q: Query> Query for Option<Q> {
+    type Item<'w> = Option<Q::Item<'w>>;
+    type Fetch<'w> = Q::Fetch<'w>;
+
+    fn add_access(access: &mut SystemAccess) {
+        Q::add_access(access);
+    }
+
+    fn writes_component(type_id: &TypeId) -> bool {
+        Q::writes_component(type_id)
+    }
+
+    fn init_fetch(world: &World, ticks: SystemTicks, query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+        Q::init_fetch(world, ticks, query_writes)
+    }
+
+    fn get_smallest_pool<'f>(_fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+        None
+    }
+
+    fn matches_archetype(_fetch: &Self::Fetch<'_>, _archetype: &Archetype) -> bool {
+        true
+    }
+
+    fn fetch<'w>(
+        fetch: &Self::Fetch<'w>,
+        entity: &Entity,
+        location: EntityLocation,
+        archetype: &Archetype,
+    ) -> Option<Self::Item<'w>> {
+        Some(Q::fetch(fetch, entity, location, archetype))
+    }
+}
+
+/// Implements Query for a single tuple of Queries.
+/// A tuple matches an Entity only if every element of the tuple matches it.
+macro_rules! impl_query_for_tuple {
+    ($($term:ident),+) => {
+        #[allow(non_snake_case)]
+        impl <$($term: Query),+> Query for ($($term,)+) {
+            type Item<'w> = ($($term::Item<'w>,)+);
+            type Fetch<'w> = ($($term::Fetch<'w>,)+);
+
+            fn add_access(access: &mut SystemAccess) {
+                $($term::add_access(access);)+
+            }
+
+            fn writes_component(type_id: &TypeId) -> bool {
+                $($term::writes_component(type_id))||+
+            }
+
+            fn init_fetch(world: &World, ticks: SystemTicks, query_writes: fn(&TypeId) -> bool) -> Self::Fetch<'_> {
+                ($($term::init_fetch(world, ticks, query_writes),)+)
+            }
+
+            fn get_smallest_pool<'f>(fetch: &'f Self::Fetch<'_>) -> Option<&'f [Entity]> {
+                let ($($term,)+) = fetch;
+                let mut smallest_pool: Option<&[Entity]> = None;
+                $(
+                    if let Some(pool) = $term::get_smallest_pool($term) {
+                        if smallest_pool.is_none_or(| smallest_pool | pool.len() < smallest_pool.len()) {
+                            smallest_pool = Some(pool);
+                        }
+                    }
+                )+
+                smallest_pool
+            }
+
+            fn matches_archetype(fetch: &Self::Fetch<'_>, archetype: &Archetype) -> bool {
+                let ($($term,)+) = fetch;
+                $($term::matches_archetype($term, archetype))&&+
+            }
+
+            fn fetch<'w>(
+                fetch: &Self::Fetch<'w>,
+                entity: &Entity,
+                location: EntityLocation,
+                archetype: &Archetype,
+            ) -> Option<Self::Item<'w>> {
+                let ($($term,)+) = fetch;
+                // Bail out as soon as one element of the tuple doesn't match
+                Some(($($term::fetch($term, entity, location, archetype)?,)+))
+            }
+        }
+    };
+}
+
+/// Implements Query for tuples of every length from the given list of type parameters down to one.
+/// e.g., `impl_query_for_tuples!(A, B, C)` implements Query for `(A, B, C)`, `(B, C)` and `(C,)`.
+macro_rules! impl_query_for_tuples {
+    ($head:ident) => {
+        impl_query_for_tuple!($head);
+    };
+    ($head:ident, $($tail:ident),+) => {
+        impl_query_for_tuple!($head, $($tail),+);
+        impl_query_for_tuples!($($tail),+);
+    };
+}
+
+// Get all Entities that have every component in the tuple (up to 12 components).
+// Each element chooses its own mutability, so read-only components can still be shared.
+// # Examples:
+// ```
+// world.query::<(&mut Transform, &Collider, &Velocity)>()
+//     .for_each(| entity, (transform, collider, velocity) | {
+//         ...
+//     })
+// ```
+impl_query_for_tuples!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+/// Where a QueryIter is up to.
+enum QueryCursor {
+    /// Walking the dense Entity array of the smallest ComponentPool in the Query.
+    /// The array is borrowed by the QueryIter's fetch, so can't change while the QueryIter is alive.
+    Pool {
+        entities: NonNull<[Entity]>,
+        index: usize,
+    },
+    /// Walking every row of every archetype that can match the Query.
+    Archetypes {
+        archetype_id: ArchetypeId,
+        row: usize,
+    },
+}
+
+/// Iterates over the Entities matching a Query, and the Query's result for each of them.
+/// Borrows every component storage in the Query once, when created, and doesn't allocate.
+/// See World::query.
+pub struct QueryIter<'w, Q: Query> {
+    fetch: Q::Fetch<'w>,
+    archetypes: Ref<'w, Archetypes>,
+    cursor: QueryCursor,
+    /// The World's workers, used by `par_for_each`.
+    thread_pool: &'w Mutex<ThreadPool>,
+}
+
+impl<'w, Q: Query> QueryIter<'w, Q> {
+    pub(super) fn new(
+        world: &'w World,
+        ticks: SystemTicks,
+    ) -> QueryIter<'w, Q> {
+        let fetch = Q::init_fetch(world, ticks, Q::writes_component);
+        let archetypes = world.archetypes.borrow();
+        // Walk whichever is shorter: the smallest ComponentPool in the Query,
+        // or the rows of every archetype that could match the Query
+        let cursor = match Q::get_smallest_pool(&fetch) {
+            Some(entities) if entities.len() <= archetypes
+                .iter()
+                .filter(| archetype | Q::matches_archetype(&fetch, archetype))
+                .map(| archetype | archetype.get_entities().len())
+                .sum() => {
+                QueryCursor::Pool { entities: NonNull::from(entities), index: 0 }
+            },
+            _ => QueryCursor::Archetypes { archetype_id: 0, row: 0 },
+        };
+        QueryIter {
+            fetch,
+            archetypes,
+            cursor,
+            thread_pool: &world.thread_pool,
+        }
+    }
+}
+
+impl<'w, Q: Query> Iterator for QueryIter<'w, Q> {
+    type Item = (Entity, Q::Item<'w>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match &mut self.cursor {
+                QueryCursor::Pool { entities, index } => {
+                    // SAFETY: the pool is borrowed by `self.fetch` for as long as this QueryIter is alive
+                    let entity = *unsafe { entities.as_ref() }.get(*index)?;
+                    *index += 1;
+                    let location = self.archetypes.get_location(&entity);
+                    let archetype = self.archetypes.get(location.archetype);
+                    if let Some(item) = Q::fetch(&self.fetch, &entity, location, archetype) {
+                        return Some((entity, item))
+                    }
+                },
+                QueryCursor::Archetypes { archetype_id, row } => {
+                    let archetype = self.archetypes.try_get(*archetype_id)?;
+                    // Move on to the next archetype once this one is finished, or if it can't match at all
+                    if *row >= archetype.get_entities().len() || (*row == 0 && !Q::matches_archetype(&self.fetch, archetype)) {
+                        *archetype_id += 1;
+                        *row = 0;
+                        continue
+                    }
+                    let entity = archetype.get_entities()[*row];
+                    let location = EntityLocation { archetype: *archetype_id, row: *row };
+                    *row += 1;
+                    if let Some(item) = Q::fetch(&self.fetch, &entity, location, archetype) {
+                        return Some((entity, item))
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// The number of possible matches each thread takes at a time in QueryIter::par_for_each.
+/// Queries with fewer possible matches than this are iterated on the calling thread.
+const PAR_BATCH_SIZE: usize = 256;
+
+/// A contiguous range of possible matches of a Query, handed to a single thread by QueryIter::par_for_each.
+enum QueryBatch<'a> {
+    /// Part of the dense Entity array of the smallest ComponentPool in the Query.
+    Pool(&'a [Entity]),
+    /// Some of the rows of an archetype that can match the Query.
+    Archetype {
+        archetype_id: ArchetypeId,
+        rows: Range<usize>,
+    },
+}
+
+/// The remaining possible matches of a Query, split into batches of at most PAR_BATCH_SIZE.
+/// Batches are found by their index, so that they don't need to be collected up front.
+#[derive(Clone)]
+enum QueryBatches<'a> {
+    Pool(&'a [Entity]),
+    /// Finding a batch walks forwards through the archetypes from the last batch found,
+    /// so batches must be found in increasing order of index.
+    Archetypes {
+        /// The archetype the last batch was found in.
+        archetype_id: ArchetypeId,
+        /// The row the batches in `archetype_id` start from.
+        start_row: usize,
+        /// The index of the first batch in `archetype_id`.
+        first_batch_index: usize,
+    },
+}
+
+impl<'a> QueryBatches<'a> {
+    fn get_num_batches<Q: Query>(
+        &self,
+        fetch: &Q::Fetch<'_>,
+        archetypes: &Archetypes,
+    ) -> usize {
+        match self {
+            QueryBatches::Pool(entities) => entities.len().div_ceil(PAR_BATCH_SIZE),
+            QueryBatches::Archetypes { archetype_id, start_row, .. } => {
+                archetypes
+                    .iter()
+                    .enumerate()
+                    .skip(*archetype_id)
+                    .filter(| (_, archetype) | Q::matches_archetype(fetch, archetype))
+                    .map(| (id, archetype) | {
+                        let start_row = if id == *archetype_id { *start_row } else { 0 };
+                        archetype.get_entities().len().saturating_sub(start_row).div_ceil(PAR_BATCH_SIZE)
+                    })
+                    .sum()
+            },
+        }
+    }
+
+    /// Get the batch with index `batch_index`, or None if there are fewer batches than that.
+    fn get<Q: Query>(
+        &mut self,
+        fetch: &Q::Fetch<'_>,
+        archetypes: &Archetypes,
+        batch_index: usize,
+    ) -> Option<QueryBatch<'a>> {
+        match self {
+            QueryBatches::Pool(entities) => {
+                let start = batch_index.checked_mul(PAR_BATCH_SIZE).filter(| start | *start < entities.len())?;
+                Some(QueryBatch::Pool(&entities[start..(start + PAR_BATCH_SIZE).min(entities.len())]))
+            },
+            QueryBatches::Archetypes { archetype_id, start_row, first_batch_index } => {
+                loop {
+                    let archetype = archetypes.try_get(*archetype_id)?;
+                    let num_rows = if Q::matches_archetype(fetch, archetype) { archetype.get_entities().len() } else { 0 };
+                    let num_batches = num_rows.saturating_sub(*start_row).div_ceil(PAR_BATCH_SIZE);
+                    if batch_index < *first_batch_index + num_batches {
+                        let row = *start_row + (batch_index - *first_batch_index) * PAR_BATCH_SIZE;
+                        return Some(QueryBatch::Archetype {
+                            archetype_id: *archetype_id,
+                            rows: row..(row + PAR_BATCH_SIZE).min(num_rows),
+                        })
+                    }
+                    *archetype_id += 1;
+                    *start_row = 0;
+                    *first_batch_index += num_batches;
+                }
+            },
+        }
+    }
+}
+
+impl<'w, Q: Query> QueryIter<'w, Q> {
+    /// Call `function` on every remaining match of the Query, splitting the matches across several threads.
+    /// Matches are visited in no particular order, but each one exactly once.
+    /// Only allowed for Queries whose mutable terms can't alias. This is checked when the Query is created:
+    /// each component storage is borrowed once, so a Query that borrows a component type mutably
+    /// and refers to it anywhere else, other than in Added or Changed (e.g., `(&mut Transform, With<Transform>)`),
+    /// panics before any thread starts.
+    /// Every term's storage must also be shareable between threads (`Q::Fetch: Sync`).
+    ///
+    /// The matches are split across the World's persistent workers, which are shared with the Schedule,
+    /// so no threads are spawned per call (other than the first time each worker is needed).
+    /// While a Schedule stage is running systems in parallel, its systems are already keeping the workers busy,
+    /// so their queries (like any nested call to `par_for_each`) run every match on the calling thread instead.
+    /// # Examples:
+    /// ```
+    /// world.query::<(&Velocity, &mut Transform)>()
+    ///     .par_for_each(| entity, (velocity, mut transform) | {
+    ///         ...
+    ///     })
+    /// ```
+    pub fn par_for_each<F>(
+        self,
+        function: F,
+    )
+    where
+        F: Fn(Entity, Q::Item<'w>) + Sync,
+        Q::Fetch<'w>: Sync,
+    {
+        let batches = self.get_batches();
+        let num_threads = thread::available_parallelism()
+            .map_or(1, | num_threads | num_threads.get())
+            .min(batches.get_num_batches::<Q>(&self.fetch, &self.archetypes));
+        let next_batch = AtomicUsize::new(0);
+        let run_batches = | | {
+            // Each thread finds its own batches, rather than them all being collected up front
+            let mut batches = batches.clone();
+            loop {
+                let batch_index = next_batch.fetch_add(1, Ordering::Relaxed);
+                let Some(batch) = batches.get::<Q>(&self.fetch, &self.archetypes, batch_index) else {
+                    return
+                };
+                Self::run_batch(&self.fetch, &self.archetypes, &batch, &function);
+            }
+        };
+        if num_threads <= 1 {
+            return run_batches()
+        }
+        // The workers are still usable if a batch panicked while they were locked
+        let thread_pool = match self.thread_pool.try_lock() {
+            Ok(thread_pool) => Some(thread_pool),
+            Err(TryLockError::Poisoned(error)) => Some(error.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        };
+        match thread_pool {
+            Some(mut thread_pool) => {
+                thread_pool.reserve_workers(num_threads - 1);
+                thread_pool.run(num_threads - 1, &run_batches, run_batches);
+            },
+            None => run_batches(),
+        }
+    }
+
+    /// Get the remaining possible matches, to be split into batches.
+    fn get_batches(&self) -> QueryBatches<'_> {
+        match &self.cursor {
+            QueryCursor::Pool { entities, index } => {
+                // SAFETY: the pool is borrowed by `self.fetch` for as long as this QueryIter is alive
+                QueryBatches::Pool(&unsafe { entities.as_ref() }[*index..])
+            },
+            QueryCursor::Archetypes { archetype_id, row } => QueryBatches::Archetypes {
+                archetype_id: *archetype_id,
+                start_row: *row,
+                first_batch_index: 0,
+            },
+        }
+    }
+
+    /// Call `function` on every match of the Query in `batch`.
+    /// Takes the QueryIter's fields separately, as the QueryIter itself can't be shared between threads.
+    fn run_batch<F>(
+        fetch: &Q::Fetch<'w>,
+        archetypes: &Archetypes,
+        batch: &QueryBatch,
+        function: &F,
+    )
+    where F: Fn(Entity, Q::Item<'w>) {
+        match batch {
+            QueryBatch::Pool(entities) => {
+                for entity in entities.iter() {
+                    let location = archetypes.get_location(entity);
+                    let archetype = archetypes.get(location.archetype);
+                    if let Some(item) = Q::fetch(fetch, entity, location, archetype) {
+                        function(*entity, item);
+                    }
+                }
+            },
+            QueryBatch::Archetype { archetype_id, rows } => {
+                let archetype = archetypes.get(*archetype_id);
+                for row in rows.clone() {
+                    let entity = archetype.get_entities()[row];
+                    let location = EntityLocation { archetype: *archetype_id, row };
+                    if let Some(item) = Q::fetch(fetch, &entity, location, archetype) {
+                        function(entity, item);
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex, thread::{self, ThreadId}};
+
+    use crate::ecs::{Changed, Entity, QueryParam, Res, ResMut, Schedule, StorageType, World};
+
+    use super::PAR_BATCH_SIZE;
+
+    struct Health(u32);
+
+    struct NumHealed(usize);
+
+    /// Spawn three Entities with Health, destroy the middle one, then create a new Entity that reuses its ID.
+    fn spawn_and_reuse(world: &mut World) -> (Entity, Entity, Entity, Entity) {
+        let first = world.spawn((Health(1),)).unwrap();
+        let destroyed = world.spawn((Health(2),)).unwrap();
+        let last = world.spawn((Health(3),)).unwrap();
+        world.destroy_entity(destroyed);
+        let reused = world.create_entity().unwrap();
+        assert_ne!(reused, destroyed);
+        (first, destroyed, last, reused)
+    }
+
+    fn query_health(world: &World) -> Vec<(Entity, u32)> {
+        let mut matches: Vec<(Entity, u32)> = world.query::<&Health>()
+            .map(| (entity, health) | (entity, health.0))
+            .collect();
+        matches.sort_by_key(| (_, health) | *health);
+        matches
+    }
+
+    fn check_query_after_destroy_and_reuse(storage_type: StorageType) {
+        let mut world = World::new();
+        world.register_component_with_storage::<Health>(storage_type);
+        let (first, _, last, reused) = spawn_and_reuse(&mut world);
+        assert_eq!(query_health(&world), vec![(first, 1), (last, 3)]);
+
+        world.add_component(&reused, Health(4)).unwrap();
+        assert_eq!(query_health(&world), vec![(first, 1), (last, 3), (reused, 4)]);
+        for (_, mut health) in world.query::<&mut Health>() {
+            health.0 *= 10;
+        }
+        assert_eq!(query_health(&world), vec![(first, 10), (last, 30), (reused, 40)]);
+    }
+
+    #[test]
+    fn pool_query_after_destroy_and_reuse() {
+        check_query_after_destroy_and_reuse(StorageType::SparseSet);
+    }
+
+    #[test]
+    fn archetype_query_after_destroy_and_reuse() {
+        check_query_after_destroy_and_reuse(StorageType::Table);
+    }
+
+    /// Heals every Entity whose Health changed since the system last ran, which doesn't count as a change for itself.
+    fn heal_changed_system(
+        changed: QueryParam<(&mut Health, Changed<Health>)>,
+        mut num_healed: ResMut<NumHealed>,
+    ) {
+        for (_, (mut health, _)) in &changed {
+            health.0 += 1;
+            num_healed.0 += 1;
+        }
+    }
+
+    fn check_mut_and_changed_of_same_component(storage_type: StorageType) {
+        let mut world = World::new();
+        world.register_component_with_storage::<Health>(storage_type);
+        world.insert_resource(NumHealed(0));
+        let damaged = world.spawn((Health(1),)).unwrap();
+        world.spawn((Health(2),)).unwrap();
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update");
+        schedule.add_system("update", "heal_changed", heal_changed_system);
+
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<NumHealed>().unwrap().0, 2);
+        world.get_component_mut::<Health>(&damaged).unwrap().unwrap().0 -= 1;
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<NumHealed>().unwrap().0, 3);
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<NumHealed>().unwrap().0, 3);
+        assert_eq!(world.get_component::<Health>(&damaged).unwrap().unwrap().0, 2);
+    }
+
+    #[test]
+    fn pool_query_with_mut_and_changed_of_same_component() {
+        check_mut_and_changed_of_same_component(StorageType::SparseSet);
+    }
+
+    #[test]
+    fn archetype_query_with_mut_and_changed_of_same_component() {
+        check_mut_and_changed_of_same_component(StorageType::Table);
+    }
+
+    /// The threads that have run a batch of `par_for_each`.
+    struct BatchThreads(Mutex<HashSet<ThreadId>>);
+
+    fn spawn_for_par_for_each(world: &mut World) {
+        for health in 0..(PAR_BATCH_SIZE * 8) as u32 {
+            world.spawn((Health(health),)).unwrap();
+        }
+    }
+
+    #[test]
+    fn par_for_each_reuses_workers() {
+        let mut world = World::new();
+        spawn_for_par_for_each(&mut world);
+        let batch_threads = Mutex::new(HashSet::new());
+        for _ in 0..4 {
+            world.query::<&mut Health>().par_for_each(| _, mut health | {
+                health.0 += 1;
+                batch_threads.lock().unwrap().insert(thread::current().id());
+            });
+        }
+        let num_threads = thread::available_parallelism().map_or(1, | num_threads | num_threads.get());
+        assert!(batch_threads.into_inner().unwrap().len() <= num_threads);
+    }
+
+    fn par_heal_system(
+        query: QueryParam<&mut Health>,
+        batch_threads: Res<BatchThreads>,
+    ) {
+        query.par_for_each(| _, mut health | {
+            health.0 += 1;
+            batch_threads.0.lock().unwrap().insert(thread::current().id());
+        });
+    }
+
+    fn other_system(_num_healed: ResMut<NumHealed>) {}
+
+    #[test]
+    fn par_for_each_in_parallel_stage_runs_on_calling_thread() {
+        let mut world = World::new();
+        spawn_for_par_for_each(&mut world);
+        world.insert_resource(BatchThreads(Mutex::new(HashSet::new())));
+        world.insert_resource(NumHealed(0));
+        let mut schedule = Schedule::new();
+        schedule.set_num_threads(2);
+        schedule.add_stage("update");
+        schedule.add_system("update", "par_heal", par_heal_system);
+        schedule.add_system("update", "other", other_system);
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<BatchThreads>().unwrap().0.lock().unwrap().len(), 1);
+        assert_eq!(world.query::<&Health>().map(| (_, health) | health.0 as usize).sum::<usize>(), (1..=PAR_BATCH_SIZE * 8).sum());
+    }
+}
@@ -0,0 +1,375 @@
+use std::thread;
+
+use super::{executor::ParallelExecutor, IntoSystem, System, World};
+
+type BoxedSystem = Box<dyn System>;
+
+/// A system stored in a Schedule, along with its ordering constraints.
+struct SystemEntry {
+    name: &'static str,
+    system: BoxedSystem,
+    /// Names of systems in the same stage that this system must run before.
+    before: Vec<&'static str>,
+    /// Names of systems in the same stage that this system must run after.
+    after: Vec<&'static str>,
+}
+
+/// A named group of systems. Every system in a stage runs before any system in the next stage.
+struct SystemStage {
+    name: &'static str,
+    systems: Vec<SystemEntry>,
+    /// `dependencies[i]` are the indices of the earlier systems that must finish before system i can start:
+    /// those it is explicitly ordered after, and those whose access conflicts with its own.
+    dependencies: Vec<Vec<usize>>,
+    /// Have systems (or ordering constraints) been added since `systems` was last sorted?
+    is_dirty: bool,
+}
+
+impl SystemStage {
+    fn get_system_index(
+        &self,
+        name: &str,
+    ) -> usize {
+        self.systems
+            .iter()
+            .position(| system_entry | system_entry.name == name)
+            .unwrap_or_else(| | panic!("Ordering constraint refers to system \"{}\", which is not in stage \"{}\"!", name, self.name))
+    }
+
+    /// Sort the systems to satisfy every before/after constraint.
+    /// Systems that aren't constrained relative to each other keep the order they were added in.
+    fn sort_systems(&mut self) {
+        let num_systems = self.systems.len();
+        // `successors[i]` are the indices of the systems that must run after system i
+        let mut successors = vec![Vec::new(); num_systems];
+        let mut num_predecessors = vec![0; num_systems];
+        for (index, system_entry) in self.systems.iter().enumerate() {
+            for name in &system_entry.before {
+                let other_index = self.get_system_index(name);
+                successors[index].push(other_index);
+                num_predecessors[other_index] += 1;
+            }
+            for name in &system_entry.after {
+                let other_index = self.get_system_index(name);
+                successors[other_index].push(index);
+                num_predecessors[index] += 1;
+            }
+        }
+
+        let mut order = Vec::with_capacity(num_systems);
+        let mut is_placed = vec![false; num_systems];
+        while order.len() < num_systems {
+            // Always place the earliest-added system that is ready, so the order is deterministic
+            let index = (0..num_systems)
+                .find(| index | !is_placed[*index] && num_predecessors[*index] == 0)
+                .unwrap_or_else(| | panic!("Systems in stage \"{}\" have cyclic ordering constraints!", self.name));
+            is_placed[index] = true;
+            for successor in &successors[index] {
+                num_predecessors[*successor] -= 1;
+            }
+            order.push(index);
+        }
+
+        let mut systems: Vec<Option<SystemEntry>> = self.systems.drain(..).map(Some).collect();
+        self.systems = order
+            .into_iter()
+            .map(| index | systems[index].take().unwrap())
+            .collect();
+        self.update_dependencies();
+        self.is_dirty = false;
+    }
+
+    /// Find the systems each system must wait for when systems run in parallel.
+    /// A system waits for every earlier system that it conflicts with, so conflicting systems
+    /// always run in the sorted order, and the stage has the same result as running on a single thread.
+    fn update_dependencies(&mut self) {
+        self.dependencies = self.systems
+            .iter()
+            .enumerate()
+            .map(| (index, system_entry) | {
+                (0..index)
+                    .filter(| other_index | {
+                        let other_entry = &self.systems[*other_index];
+                        system_entry.after.contains(&other_entry.name) ||
+                        other_entry.before.contains(&system_entry.name) ||
+                        !system_entry.system.get_access().is_compatible(other_entry.system.get_access())
+                    })
+                    .collect()
+            })
+            .collect();
+    }
+}
+
+/// Returned by Schedule::add_system, to constrain when the system runs within its stage.
+/// # Examples:
+/// ```
+/// schedule.add_system("physics", "collision_detection", collision_detection_system)
+///     .after("apply_velocity")
+///     .before("collision_resolution");
+/// ```
+pub struct SystemConfig<'s> {
+    system_entry: &'s mut SystemEntry,
+}
+
+impl SystemConfig<'_> {
+    /// Run this system before the system called `name` (which must be in the same stage).
+    #[allow(dead_code)]
+    pub fn before(
+        self,
+        name: &'static str,
+    ) -> Self {
+        self.system_entry.before.push(name);
+        self
+    }
+
+    /// Run this system after the system called `name` (which must be in the same stage).
+    pub fn after(
+        self,
+        name: &'static str,
+    ) -> Self {
+        self.system_entry.after.push(name);
+        self
+    }
+}
+
+/// Runs systems on a World in named stages.
+/// Stages run in the order they were added. Within a stage, systems run in the order they were added,
+/// unless constrained with SystemConfig::before/after.
+/// Each stage ends with a sync point, where the Commands recorded by its systems are applied to the World.
+///
+/// Systems in a stage run in parallel, unless their access conflicts (see SystemAccess::is_compatible)
+/// or they are explicitly ordered, in which case they run in the order above.
+/// Running in parallel doesn't change the result of a stage, with one exception:
+/// Entities spawned by Commands in systems that ran at the same time may be given their IDs in either order.
+pub struct Schedule {
+    stages: Vec<SystemStage>,
+    /// How many threads (including the calling thread) to run systems on.
+    /// The threads other than the calling thread are the World's workers, which are kept between runs.
+    num_threads: usize,
+}
+
+impl Schedule {
+    /// Create a Schedule that runs systems on as many threads as the machine can run in parallel.
+    pub fn new() -> Schedule {
+        Schedule {
+            stages: Vec::new(),
+            num_threads: thread::available_parallelism().map_or(1, | num_threads | num_threads.get()),
+        }
+    }
+
+    /// Set how many threads (including the calling thread) to run systems on.
+    /// With a single thread, every system runs on the calling thread, in order.
+    #[allow(dead_code)]
+    pub fn set_num_threads(
+        &mut self,
+        num_threads: usize,
+    ) -> &mut Self {
+        assert!(num_threads > 0, "Schedule needs at least one thread to run systems on!");
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Add a stage, to run after every stage added so far.
+    pub fn add_stage(
+        &mut self,
+        name: &'static str,
+    ) -> &mut Self {
+        assert!(self.stages.iter().all(| stage | stage.name != name), "Schedule already has a stage called \"{}\"!", name);
+        self.stages.push(SystemStage {
+            name,
+            systems: Vec::new(),
+            dependencies: Vec::new(),
+            is_dirty: false,
+        });
+        self
+    }
+
+    /// Add a system to a stage. The system's name must be unique within the stage,
+    /// so that other systems can be ordered relative to it.
+    pub fn add_system<S, Marker>(
+        &mut self,
+        stage_name: &'static str,
+        name: &'static str,
+        system: S,
+    ) -> SystemConfig<'_>
+    where
+        S: IntoSystem<Marker>,
+        S::System: 'static,
+    {
+        let stage = self.stages
+            .iter_mut()
+            .find(| stage | stage.name == stage_name)
+            .unwrap_or_else(| | panic!("Tried to add system \"{}\" to stage \"{}\", which is not in the schedule!", name, stage_name));
+        assert!(stage.systems.iter().all(| system_entry | system_entry.name != name), "Stage \"{}\" already has a system called \"{}\"!", stage_name, name);
+        stage.systems.push(SystemEntry {
+            name,
+            system: Box::new(system.into_system()),
+            before: Vec::new(),
+            after: Vec::new(),
+        });
+        stage.is_dirty = true;
+        SystemConfig {
+            system_entry: stage.systems.last_mut().unwrap(),
+        }
+    }
+
+    /// Run every stage once, applying each stage's Commands at the end of the stage.
+    pub fn run(
+        &mut self,
+        world: &mut World,
+    ) {
+        for stage in &mut self.stages {
+            if stage.is_dirty {
+                stage.sort_systems();
+            }
+            let num_threads = self.num_threads.min(stage.systems.len());
+            if num_threads <= 1 {
+                for system_entry in &mut stage.systems {
+                    system_entry.system.run(world);
+                }
+            } else {
+                let systems = stage.systems
+                    .iter_mut()
+                    .map(| system_entry | &mut system_entry.system)
+                    .collect();
+                // The workers stay locked for the whole stage, so queries in its systems don't use them too
+                // (see QueryIter::par_for_each). The pool is still usable if a system panicked while it was locked
+                let mut thread_pool = world.thread_pool.lock().unwrap_or_else(| error | error.into_inner());
+                thread_pool.reserve_workers(num_threads - 1);
+                ParallelExecutor::new(systems, &stage.dependencies).run(world, &thread_pool, num_threads - 1);
+            }
+            // Sync point: nothing in the World is borrowed, so structural changes can be made.
+            // Commands are applied in the same order as the systems ran
+            for system_entry in &mut stage.systems {
+                system_entry.system.apply_commands(world);
+            }
+            // Including those recorded by hooks that systems triggered directly, rather than through Commands
+            world.apply_hook_commands();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicUsize, Ordering}, Arc}, thread, time::{Duration, Instant}};
+
+    use crate::ecs::{Changed, QueryParam, ResMut, Schedule, World};
+
+    /// Counts the systems that have reached the rendezvous.
+    struct Rendezvous {
+        num_arrived: Arc<AtomicUsize>,
+        has_met: bool,
+    }
+
+    struct A(Rendezvous);
+    struct B(Rendezvous);
+
+    /// Wait for both systems to arrive, which can only happen if they are running at the same time.
+    fn meet(rendezvous: &mut Rendezvous) {
+        rendezvous.num_arrived.fetch_add(1, Ordering::AcqRel);
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if rendezvous.num_arrived.load(Ordering::Acquire) == 2 {
+                rendezvous.has_met = true;
+                return
+            }
+            thread::yield_now();
+        }
+    }
+
+    fn meet_a_system(mut a: ResMut<A>) {
+        meet(&mut a.0);
+    }
+
+    fn meet_b_system(mut b: ResMut<B>) {
+        meet(&mut b.0);
+    }
+
+    #[test]
+    fn non_conflicting_systems_overlap() {
+        let num_arrived = Arc::new(AtomicUsize::new(0));
+        let mut world = World::new();
+        world.insert_resource(A(Rendezvous { num_arrived: Arc::clone(&num_arrived), has_met: false }));
+        world.insert_resource(B(Rendezvous { num_arrived, has_met: false }));
+        let mut schedule = Schedule::new();
+        schedule.set_num_threads(2);
+        schedule.add_stage("update");
+        schedule.add_system("update", "meet_a", meet_a_system);
+        schedule.add_system("update", "meet_b", meet_b_system);
+        schedule.run(&mut world);
+        assert!(world.get_resource::<A>().unwrap().0.has_met);
+        assert!(world.get_resource::<B>().unwrap().0.has_met);
+    }
+
+    struct Log(Vec<&'static str>);
+
+    fn slow_first_system(mut log: ResMut<Log>) {
+        // Gives the second system every chance to overtake, if the executor would let it
+        thread::sleep(Duration::from_millis(50));
+        log.0.push("first");
+    }
+
+    fn second_system(mut log: ResMut<Log>) {
+        log.0.push("second");
+    }
+
+    #[test]
+    fn conflicting_systems_keep_their_order() {
+        let mut world = World::new();
+        world.insert_resource(Log(Vec::new()));
+        let mut schedule = Schedule::new();
+        schedule.set_num_threads(2);
+        schedule.add_stage("update");
+        schedule.add_system("update", "first", slow_first_system);
+        schedule.add_system("update", "second", second_system);
+        for _ in 0..3 {
+            schedule.run(&mut world);
+        }
+        assert_eq!(world.get_resource::<Log>().unwrap().0, ["first", "second"].repeat(3));
+    }
+
+    struct Position(u32);
+
+    struct NumChangesSeen(usize);
+
+    fn move_system(positions: QueryParam<&mut Position>) {
+        for (_, mut position) in &positions {
+            position.0 += 1;
+        }
+    }
+
+    fn count_changes_system(
+        changed: QueryParam<Changed<Position>>,
+        mut num_changes_seen: ResMut<NumChangesSeen>,
+    ) {
+        num_changes_seen.0 += changed.iter().count();
+    }
+
+    /// Run `move_system` and `count_changes_system` in the given order, checking every change is seen exactly once.
+    fn check_changes_seen(move_first: bool) {
+        let mut world = World::new();
+        world.insert_resource(NumChangesSeen(0));
+        world.spawn((Position(0),)).unwrap();
+        let mut schedule = Schedule::new();
+        schedule.set_num_threads(1);
+        schedule.add_stage("update");
+        if move_first {
+            schedule.add_system("update", "move", move_system);
+            schedule.add_system("update", "count_changes", count_changes_system);
+        } else {
+            schedule.add_system("update", "count_changes", count_changes_system);
+            schedule.add_system("update", "move", move_system);
+        }
+        for num_runs in 1..=4 {
+            schedule.run(&mut world);
+            assert_eq!(world.get_resource::<NumChangesSeen>().unwrap().0, num_runs);
+        }
+    }
+
+    #[test]
+    fn changes_earlier_in_a_run_are_seen_by_later_systems() {
+        check_changes_seen(true);
+        // Changes made after a system in one run are seen by it in the next
+        check_changes_seen(false);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+/// How many Entities are expected to have a SparseSet component, chosen when it is registered.
+/// Sizes the pages of the component's SparseArray. See World::register_component_with_hint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StorageHint {
+    /// Many Entities will have the component. Pages are large, so fewer of them need allocating.
+    Common,
+    /// Few Entities will have the component (e.g., only the tile map). Pages are small, and only pages in use are kept
+    /// (keyed by page index), so the component's memory scales with the number of Entities that have one,
+    /// rather than the highest Entity ID.
+    Rare,
+}
+
+impl StorageHint {
+    /// log2 of the number of entries in each page.
+    fn get_page_shift(&self) -> u32 {
+        match self {
+            StorageHint::Common => 8, // 256 entries
+            StorageHint::Rare => 4, // 16 entries
+        }
+    }
+}
+
+/// A fixed-size run of a SparseArray's entries.
+struct SparsePage {
+    entries: Box<[Option<usize>]>,
+    /// The number of entries that are Some. Once this drops to 0, the page is freed.
+    num_entries: usize,
+}
+
+/// A SparseArray's allocated pages, found by page index (an Entity ID shifted right by the page shift).
+enum SparsePages {
+    /// The page at index i is at position i, so looking up a page is just an index,
+    /// but the Vec grows with the highest page index in use. Used for StorageHint::Common.
+    Indexed(Vec<Option<SparsePage>>),
+    /// Only the pages in use, so memory doesn't grow with the highest page index. Used for StorageHint::Rare.
+    Keyed(HashMap<usize, SparsePage>),
+}
+
+impl SparsePages {
+    fn new(hint: StorageHint) -> SparsePages {
+        match hint {
+            StorageHint::Common => SparsePages::Indexed(Vec::new()),
+            StorageHint::Rare => SparsePages::Keyed(HashMap::new()),
+        }
+    }
+
+    fn get(
+        &self,
+        page_index: usize,
+    ) -> Option<&SparsePage> {
+        match self {
+            SparsePages::Indexed(pages) => pages.get(page_index)?.as_ref(),
+            SparsePages::Keyed(pages) => pages.get(&page_index),
+        }
+    }
+
+    fn get_mut(
+        &mut self,
+        page_index: usize,
+    ) -> Option<&mut SparsePage> {
+        match self {
+            SparsePages::Indexed(pages) => pages.get_mut(page_index)?.as_mut(),
+            SparsePages::Keyed(pages) => pages.get_mut(&page_index),
+        }
+    }
+
+    /// Get the page at `page_index`, allocating it with `page_size` empty entries if it isn't allocated.
+    fn get_or_insert(
+        &mut self,
+        page_index: usize,
+        page_size: usize,
+    ) -> &mut SparsePage {
+        let new_page = | | SparsePage {
+            entries: vec![None; page_size].into_boxed_slice(),
+            num_entries: 0,
+        };
+        match self {
+            SparsePages::Indexed(pages) => {
+                if pages.len() <= page_index {
+                    pages.resize_with(page_index + 1, | | None);
+                }
+                pages[page_index].get_or_insert_with(new_page)
+            },
+            SparsePages::Keyed(pages) => pages.entry(page_index).or_insert_with(new_page),
+        }
+    }
+
+    fn remove(
+        &mut self,
+        page_index: usize,
+    ) {
+        match self {
+            SparsePages::Indexed(pages) => pages[page_index] = None,
+            SparsePages::Keyed(pages) => {
+                pages.remove(&page_index);
+            },
+        }
+    }
+}
+
+/// Maps from Entity IDs to indices in a ComponentPool's dense arrays.
+/// The entries are split into fixed-size pages, which are only allocated once an Entity in their range
+/// gets an entry, and are freed again once every entry in them has been removed.
+pub(super) struct SparseArray {
+    /// Page i holds the entries for Entity IDs `i << page_shift` up to (but not including) `(i + 1) << page_shift`.
+    pages: SparsePages,
+    /// log2 of the number of entries in each page, so that an ID's page and offset are a shift and a mask away.
+    page_shift: u32,
+}
+
+impl SparseArray {
+    pub fn new(hint: StorageHint) -> SparseArray {
+        SparseArray {
+            pages: SparsePages::new(hint),
+            page_shift: hint.get_page_shift(),
+        }
+    }
+
+    pub fn get(
+        &self,
+        index: usize,
+    ) -> Option<usize> {
+        let page = self.pages.get(index >> self.page_shift)?;
+        page.entries[self.get_offset(index)]
+    }
+
+    /// Set the entry at `index`, replacing its old value if it had one. Allocates its page if needed.
+    pub fn insert(
+        &mut self,
+        index: usize,
+        value: usize,
+    ) {
+        let offset = self.get_offset(index);
+        let page = self.pages.get_or_insert(index >> self.page_shift, 1 << self.page_shift);
+        if page.entries[offset].replace(value).is_none() {
+            page.num_entries += 1;
+        }
+    }
+
+    /// Remove the entry at `index`, returning its value (if it had one). Frees its page if it was the last entry.
+    pub fn remove(
+        &mut self,
+        index: usize,
+    ) -> Option<usize> {
+        let offset = self.get_offset(index);
+        let page_index = index >> self.page_shift;
+        let page = self.pages.get_mut(page_index)?;
+        let value = page.entries[offset].take()?;
+        page.num_entries -= 1;
+        if page.num_entries == 0 {
+            self.pages.remove(page_index);
+        }
+        Some(value)
+    }
+
+    fn get_offset(
+        &self,
+        index: usize,
+    ) -> usize {
+        index & ((1 << self.page_shift) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SparseArray, SparsePages, StorageHint};
+
+    #[test]
+    fn rare_arrays_only_keep_pages_in_use() {
+        let mut sparse = SparseArray::new(StorageHint::Rare);
+        sparse.insert(3, 0);
+        sparse.insert(1_000_000, 1);
+        assert_eq!(sparse.get(3), Some(0));
+        assert_eq!(sparse.get(1_000_000), Some(1));
+        assert_eq!(sparse.get(1_000_001), None);
+        let SparsePages::Keyed(pages) = &sparse.pages else { panic!("Rare SparseArray isn't keyed!") };
+        assert_eq!(pages.len(), 2);
+
+        assert_eq!(sparse.remove(1_000_000), Some(1));
+        let SparsePages::Keyed(pages) = &sparse.pages else { panic!("Rare SparseArray isn't keyed!") };
+        assert_eq!(pages.len(), 1);
+    }
+}
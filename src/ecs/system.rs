@@ -0,0 +1,674 @@
+use std::{any::TypeId, collections::HashSet, marker::PhantomData, ops::{Deref, DerefMut}};
+
+use super::{
+    cell::{Ref, RefMut},
+    event::{EventCursor, Events},
+    CommandQueue, Commands, Component, Entity, Event, Query, QueryIter, Resource, SystemTicks, Tick, World,
+};
+
+/// The component and resource types a System reads and writes.
+/// Two systems with compatible access can't borrow anything the other is borrowing mutably.
+#[derive(Default, Debug)]
+pub struct SystemAccess {
+    component_reads: HashSet<TypeId>,
+    component_writes: HashSet<TypeId>,
+    resource_reads: HashSet<TypeId>,
+    resource_writes: HashSet<TypeId>,
+    /// Does the system have access to the whole World (e.g., through a `&World` parameter)?
+    /// An exclusive system is incompatible with every other system.
+    is_exclusive: bool,
+    /// Does the system use something that can only be accessed from the main thread (e.g., a non-send resource)?
+    is_main_thread_only: bool,
+}
+
+impl SystemAccess {
+    pub fn add_component_read<T: 'static>(&mut self) {
+        self.component_reads.insert(TypeId::of::<T>());
+    }
+
+    pub fn add_component_write<T: 'static>(&mut self) {
+        self.component_writes.insert(TypeId::of::<T>());
+    }
+
+    pub fn add_resource_read<R: 'static>(&mut self) {
+        self.resource_reads.insert(TypeId::of::<R>());
+    }
+
+    pub fn add_resource_write<R: 'static>(&mut self) {
+        self.resource_writes.insert(TypeId::of::<R>());
+    }
+
+    pub fn set_exclusive(&mut self) {
+        self.is_exclusive = true;
+    }
+
+    pub fn set_main_thread_only(&mut self) {
+        self.is_main_thread_only = true;
+    }
+
+    pub fn get_component_reads(&self) -> &HashSet<TypeId> {
+        &self.component_reads
+    }
+
+    pub fn get_component_writes(&self) -> &HashSet<TypeId> {
+        &self.component_writes
+    }
+
+    pub fn get_resource_reads(&self) -> &HashSet<TypeId> {
+        &self.resource_reads
+    }
+
+    pub fn get_resource_writes(&self) -> &HashSet<TypeId> {
+        &self.resource_writes
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.is_exclusive
+    }
+
+    pub fn is_main_thread_only(&self) -> bool {
+        self.is_main_thread_only
+    }
+
+    /// Can a system with this access run at the same time as a system with `other` access?
+    /// True unless one of them writes a type that the other reads or writes.
+    pub fn is_compatible(
+        &self,
+        other: &SystemAccess,
+    ) -> bool {
+        if self.is_exclusive || other.is_exclusive {
+            return false
+        }
+        let writes_conflict = | writes: &HashSet<TypeId>, reads: &HashSet<TypeId>, other_writes: &HashSet<TypeId> | {
+            !writes.is_disjoint(reads) || !writes.is_disjoint(other_writes)
+        };
+        !writes_conflict(&self.component_writes, &other.component_reads, &other.component_writes) &&
+        !writes_conflict(&other.component_writes, &self.component_reads, &self.component_writes) &&
+        !writes_conflict(&self.resource_writes, &other.resource_reads, &other.resource_writes) &&
+        !writes_conflict(&other.resource_writes, &self.resource_reads, &self.resource_writes)
+    }
+}
+
+/// Something that can be run on a World by a Schedule.
+/// Usually created from a function whose arguments are all SystemParams (see IntoSystem).
+/// Systems are sent to worker threads to run in parallel, so they must be Send.
+pub trait System: Send {
+    /// The component and resource types this system reads and writes.
+    fn get_access(&self) -> &SystemAccess;
+    /// Run the system. Structural changes are recorded, rather than applied (see `apply_commands`).
+    fn run(&mut self, world: &World);
+    /// Apply the structural changes recorded by the system since it was last applied.
+    /// Called by the Schedule at each sync point.
+    fn apply_commands(&mut self, world: &mut World);
+}
+
+/// Something that can be passed to a system function as an argument, fetched from the World when the system runs.
+pub trait SystemParam {
+    /// Data kept between runs of the system (e.g., which events it has already read).
+    type State: Send;
+    /// The argument passed to the system function.
+    type Item<'w, 's>;
+
+    fn init_state() -> Self::State;
+    /// Record which component and resource types this parameter reads and writes.
+    fn add_access(access: &mut SystemAccess);
+    /// `ticks` are the ticks the system is running with, used for change detection (see Added and Changed).
+    fn get_param<'w, 's>(state: &'s mut Self::State, world: &'w World, ticks: SystemTicks) -> Self::Item<'w, 's>;
+    /// Apply any structural changes recorded into the state. See System::apply_commands.
+    fn apply(_state: &mut Self::State, _world: &mut World) {}
+}
+
+/// A Query, as a system parameter.
+/// # Examples:
+/// ```
+/// fn apply_velocity_system(query: QueryParam<(&Velocity, &mut Transform)>) {
+///     for (_, (velocity, mut transform)) in query.iter() {
+///         ...
+///     }
+/// }
+/// ```
+pub struct QueryParam<'w, Q: Query> {
+    world: &'w World,
+    ticks: SystemTicks,
+    marker: PhantomData<Q>,
+}
+
+impl<'w, Q: Query> QueryParam<'w, Q> {
+    /// Get Entities and references to components matching the Query. See World::query.
+    pub fn iter(&self) -> QueryIter<'w, Q> {
+        QueryIter::new(self.world, self.ticks)
+    }
+
+    /// Call `function` on every match of the Query, splitting the matches across several threads.
+    /// See QueryIter::par_for_each.
+    pub fn par_for_each<F>(
+        &self,
+        function: F,
+    )
+    where
+        F: Fn(Entity, Q::Item<'w>) + Sync,
+        Q::Fetch<'w>: Sync,
+    {
+        self.iter().par_for_each(function);
+    }
+
+    /// Get the result of the Query for a single Entity, if it is valid and matches the Query.
+    pub fn get(
+        &self,
+        entity: &Entity,
+    ) -> Option<Q::Item<'w>> {
+        if !self.world.entity_allocator.is_valid(entity) {
+            return None
+        }
+        let fetch = Q::init_fetch(self.world, self.ticks, Q::writes_component);
+        let archetypes = self.world.archetypes.borrow();
+        let location = archetypes.get_location(entity);
+        Q::fetch(&fetch, entity, location, archetypes.get(location.archetype))
+    }
+}
+
+impl<'w, Q: Query> IntoIterator for &QueryParam<'w, Q> {
+    type Item = (Entity, Q::Item<'w>);
+    type IntoIter = QueryIter<'w, Q>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<Q: Query> SystemParam for QueryParam<'_, Q> {
+    type State = ();
+    type Item<'w, 's> = QueryParam<'w, Q>;
+
+    fn init_state() -> Self::State {}
+
+    fn add_access(access: &mut SystemAccess) {
+        Q::add_access(access);
+    }
+
+    fn get_param<'w, 's>(_state: &'s mut Self::State, world: &'w World, ticks: SystemTicks) -> Self::Item<'w, 's> {
+        QueryParam {
+            world,
+            ticks,
+            marker: PhantomData,
+        }
+    }
+}
+
+/// An immutably borrowed resource, as a system parameter.
+/// Panics when the system runs if the World doesn't have a resource of type R.
+pub struct Res<'w, R: Resource> {
+    resource: Ref<'w, R>,
+}
+
+impl<R: Resource> Deref for Res<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.resource
+    }
+}
+
+impl<R: Resource> SystemParam for Res<'_, R> {
+    type State = ();
+    type Item<'w, 's> = Res<'w, R>;
+
+    fn init_state() -> Self::State {}
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_resource_read::<R>();
+    }
+
+    fn get_param<'w, 's>(_state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        Res {
+            resource: world
+                .get_resource::<R>()
+                .unwrap_or_else(| | panic!("System requested a {} resource, which is missing from the world!", std::any::type_name::<R>())),
+        }
+    }
+}
+
+/// A mutably borrowed resource, as a system parameter.
+/// Panics when the system runs if the World doesn't have a resource of type R.
+pub struct ResMut<'w, R: Resource> {
+    resource: RefMut<'w, R>,
+}
+
+impl<R: Resource> Deref for ResMut<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.resource
+    }
+}
+
+impl<R: Resource> DerefMut for ResMut<'_, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        &mut self.resource
+    }
+}
+
+impl<R: Resource> SystemParam for ResMut<'_, R> {
+    type State = ();
+    type Item<'w, 's> = ResMut<'w, R>;
+
+    fn init_state() -> Self::State {}
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_resource_write::<R>();
+    }
+
+    fn get_param<'w, 's>(_state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        ResMut {
+            resource: world
+                .get_resource_mut::<R>()
+                .unwrap_or_else(| | panic!("System requested a {} resource, which is missing from the world!", std::any::type_name::<R>())),
+        }
+    }
+}
+
+/// An immutably borrowed non-send resource, as a system parameter. Systems with this parameter always run on the main thread.
+/// Panics when the system runs if the World doesn't have a non-send resource of type R.
+#[allow(dead_code)]
+pub struct NonSend<'w, R: 'static> {
+    resource: Ref<'w, R>,
+}
+
+impl<R: 'static> Deref for NonSend<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.resource
+    }
+}
+
+impl<R: 'static> SystemParam for NonSend<'_, R> {
+    type State = ();
+    type Item<'w, 's> = NonSend<'w, R>;
+
+    fn init_state() -> Self::State {}
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_resource_read::<R>();
+        access.set_main_thread_only();
+    }
+
+    fn get_param<'w, 's>(_state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        NonSend {
+            resource: world
+                .get_non_send_resource::<R>()
+                .unwrap_or_else(| | panic!("System requested a {} non-send resource, which is missing from the world!", std::any::type_name::<R>())),
+        }
+    }
+}
+
+/// A mutably borrowed non-send resource, as a system parameter. Systems with this parameter always run on the main thread.
+/// Panics when the system runs if the World doesn't have a non-send resource of type R.
+pub struct NonSendMut<'w, R: 'static> {
+    resource: RefMut<'w, R>,
+}
+
+impl<R: 'static> Deref for NonSendMut<'_, R> {
+    type Target = R;
+
+    fn deref(&self) -> &R {
+        &self.resource
+    }
+}
+
+impl<R: 'static> DerefMut for NonSendMut<'_, R> {
+    fn deref_mut(&mut self) -> &mut R {
+        &mut self.resource
+    }
+}
+
+impl<R: 'static> SystemParam for NonSendMut<'_, R> {
+    type State = ();
+    type Item<'w, 's> = NonSendMut<'w, R>;
+
+    fn init_state() -> Self::State {}
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_resource_write::<R>();
+        access.set_main_thread_only();
+    }
+
+    fn get_param<'w, 's>(_state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        NonSendMut {
+            resource: world
+                .get_non_send_resource_mut::<R>()
+                .unwrap_or_else(| | panic!("System requested a {} non-send resource, which is missing from the world!", std::any::type_name::<R>())),
+        }
+    }
+}
+
+/// Reads events of type T, as a system parameter.
+/// Each system keeps its own EventCursor, so it only sees each event once.
+pub struct EventReader<'w, 's, T: Event> {
+    events: Ref<'w, Events<T>>,
+    cursor: &'s mut EventCursor<T>,
+}
+
+impl<T: Event> EventReader<'_, '_, T> {
+    /// Iterate over every event this system hasn't read yet, oldest first.
+    pub fn read(&mut self) -> impl Iterator<Item = &T> {
+        self.cursor.read(&self.events)
+    }
+}
+
+impl<T: Event> SystemParam for EventReader<'_, '_, T> {
+    type State = EventCursor<T>;
+    type Item<'w, 's> = EventReader<'w, 's, T>;
+
+    fn init_state() -> Self::State {
+        EventCursor::new()
+    }
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_resource_read::<Events<T>>();
+    }
+
+    fn get_param<'w, 's>(state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        EventReader {
+            events: world
+                .get_resource::<Events<T>>()
+                .unwrap_or_else(| | panic!("System requested {} events, which were not registered!", std::any::type_name::<T>())),
+            cursor: state,
+        }
+    }
+}
+
+/// Sends events of type T, as a system parameter.
+pub struct EventWriter<'w, T: Event> {
+    events: RefMut<'w, Events<T>>,
+}
+
+impl<T: Event> EventWriter<'_, T> {
+    pub fn send(
+        &mut self,
+        event: T,
+    ) {
+        self.events.send(event);
+    }
+}
+
+impl<T: Event> SystemParam for EventWriter<'_, T> {
+    type State = ();
+    type Item<'w, 's> = EventWriter<'w, T>;
+
+    fn init_state() -> Self::State {}
+
+    fn add_access(access: &mut SystemAccess) {
+        access.add_resource_write::<Events<T>>();
+    }
+
+    fn get_param<'w, 's>(_state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        EventWriter {
+            events: world
+                .get_resource_mut::<Events<T>>()
+                .unwrap_or_else(| | panic!("System requested {} events, which were not registered!", std::any::type_name::<T>())),
+        }
+    }
+}
+
+/// Reads the Entities that have lost a component of type T, as a system parameter.
+/// See World::get_removed_components. Each system keeps its own EventCursor, so it only sees each removal once.
+/// # Examples:
+/// ```
+/// fn despawn_effect_system(mut removed_enemies: RemovedComponents<Enemy>) {
+///     for entity in removed_enemies.read() {
+///         ...
+///     }
+/// }
+/// ```
+pub struct RemovedComponents<'w, 's, T: Component> {
+    /// None if T isn't registered, in which case no Entity can have lost one.
+    removed: Option<Ref<'w, Events<Entity>>>,
+    cursor: &'s mut EventCursor<Entity>,
+    marker: PhantomData<T>,
+}
+
+impl<T: Component> RemovedComponents<'_, '_, T> {
+    /// Iterate over every Entity that has lost a T since this system last read, oldest first.
+    pub fn read(&mut self) -> impl Iterator<Item = Entity> {
+        self.removed
+            .as_deref()
+            .map(| removed | self.cursor.read(removed))
+            .into_iter()
+            .flatten()
+            .copied()
+    }
+}
+
+impl<T: Component> SystemParam for RemovedComponents<'_, '_, T> {
+    type State = EventCursor<Entity>;
+    type Item<'w, 's> = RemovedComponents<'w, 's, T>;
+
+    fn init_state() -> Self::State {
+        EventCursor::new()
+    }
+
+    fn add_access(_access: &mut SystemAccess) {
+        // Components are only removed by exclusive systems and at sync points, so this can't conflict with anything
+    }
+
+    fn get_param<'w, 's>(state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        RemovedComponents {
+            removed: world.get_removed_components::<T>().ok(),
+            cursor: state,
+            marker: PhantomData,
+        }
+    }
+}
+
+/// Each system keeps its own CommandQueue, which is applied at the next sync point.
+impl SystemParam for Commands<'_, '_> {
+    type State = CommandQueue;
+    type Item<'w, 's> = Commands<'s, 'w>;
+
+    fn init_state() -> Self::State {
+        CommandQueue::new()
+    }
+
+    fn add_access(_access: &mut SystemAccess) {
+        // Commands only reserve Entities until they are applied
+    }
+
+    fn get_param<'w, 's>(state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        Commands::new(state, world)
+    }
+
+    fn apply(state: &mut Self::State, world: &mut World) {
+        state.apply(world);
+    }
+}
+
+/// The whole World, as a system parameter. Systems with this parameter can't run alongside any other system,
+/// and run on the main thread, as they may access non-send resources.
+impl SystemParam for &World {
+    type State = ();
+    type Item<'w, 's> = &'w World;
+
+    fn init_state() -> Self::State {}
+
+    fn add_access(access: &mut SystemAccess) {
+        access.set_exclusive();
+        access.set_main_thread_only();
+    }
+
+    fn get_param<'w, 's>(_state: &'s mut Self::State, world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+        world
+    }
+}
+
+/// Implements SystemParam for a single tuple of SystemParams.
+macro_rules! impl_system_param_for_tuple {
+    ($($param:ident),*) => {
+        #[allow(non_snake_case, clippy::unused_unit)]
+        impl<$($param: SystemParam),*> SystemParam for ($($param,)*) {
+            type State = ($($param::State,)*);
+            type Item<'w, 's> = ($($param::Item<'w, 's>,)*);
+
+            fn init_state() -> Self::State {
+                ($($param::init_state(),)*)
+            }
+
+            fn add_access(_access: &mut SystemAccess) {
+                $($param::add_access(_access);)*
+            }
+
+            fn get_param<'w, 's>(state: &'s mut Self::State, _world: &'w World, _ticks: SystemTicks) -> Self::Item<'w, 's> {
+                let ($($param,)*) = state;
+                ($($param::get_param($param, _world, _ticks),)*)
+            }
+
+            fn apply(state: &mut Self::State, _world: &mut World) {
+                let ($($param,)*) = state;
+                $($param::apply($param, _world);)*
+            }
+        }
+    };
+}
+
+/// A function that can be run as a system, taking the tuple of SystemParams P as its arguments.
+pub trait SystemParamFunction<P: SystemParam> {
+    fn run(&mut self, params: P::Item<'_, '_>);
+}
+
+/// Implements SystemParamFunction for functions taking the given SystemParams as arguments.
+macro_rules! impl_system_param_function {
+    ($($param:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<Func, $($param: SystemParam),*> SystemParamFunction<($($param,)*)> for Func
+        where
+            // The first bound lets the compiler infer the SystemParams from the function's signature,
+            // the second lets us call the function with the SystemParams' items
+            Func: FnMut($($param),*) + FnMut($($param::Item<'_, '_>),*),
+        {
+            fn run(&mut self, params: <($($param,)*) as SystemParam>::Item<'_, '_>) {
+                // Calling through this helper makes the compiler use the second FnMut bound
+                #[allow(clippy::too_many_arguments)]
+                fn call_inner<$($param),*>(
+                    mut function: impl FnMut($($param),*),
+                    $($param: $param),*
+                ) {
+                    function($($param),*)
+                }
+                let ($($param,)*) = params;
+                call_inner(self, $($param),*)
+            }
+        }
+    };
+}
+
+/// Implements SystemParam and SystemParamFunction for tuples of every length
+/// from the given list of type parameters down to zero.
+macro_rules! impl_system_params {
+    () => {
+        impl_system_param_for_tuple!();
+        impl_system_param_function!();
+    };
+    ($head:ident $(, $tail:ident)*) => {
+        impl_system_param_for_tuple!($head $(, $tail)*);
+        impl_system_param_function!($head $(, $tail)*);
+        impl_system_params!($($tail),*);
+    };
+}
+
+// Systems can take up to 12 parameters
+impl_system_params!(A, B, C, D, E, F, G, H, I, J, K, L);
+
+/// A System made from a function. See IntoSystem.
+pub struct FunctionSystem<Func, P: SystemParam> {
+    function: Func,
+    state: P::State,
+    access: SystemAccess,
+    /// The tick the system last ran at, so its parameters can detect changes since then.
+    last_run: Tick,
+}
+
+impl<Func, P> System for FunctionSystem<Func, P>
+where
+    Func: SystemParamFunction<P> + Send,
+    P: SystemParam,
+{
+    fn get_access(&self) -> &SystemAccess {
+        &self.access
+    }
+
+    fn run(&mut self, world: &World) {
+        let this_run = world.increment_change_tick();
+        let params = P::get_param(&mut self.state, world, SystemTicks { last_run: self.last_run, this_run });
+        self.function.run(params);
+        self.last_run = this_run;
+    }
+
+    fn apply_commands(&mut self, world: &mut World) {
+        P::apply(&mut self.state, world);
+    }
+}
+
+/// Converts something (usually a function) into a System.
+/// Implemented for every function whose arguments are all SystemParams.
+/// # Examples:
+/// ```
+/// fn shoot_gun_system(
+///     input: Res<Input>,
+///     guns: QueryParam<(&ShootsBullet, &Transform)>,
+///     mut commands: Commands,
+/// ) {
+///     ...
+/// }
+/// schedule.add_system("simulation", "shoot_gun", shoot_gun_system);
+/// ```
+pub trait IntoSystem<Marker> {
+    type System: System;
+
+    fn into_system(self) -> Self::System;
+}
+
+impl<Func, P> IntoSystem<P> for Func
+where
+    Func: SystemParamFunction<P> + Send,
+    P: SystemParam,
+{
+    type System = FunctionSystem<Func, P>;
+
+    fn into_system(self) -> Self::System {
+        let mut access = SystemAccess::default();
+        P::add_access(&mut access);
+        FunctionSystem {
+            function: self,
+            state: P::init_state(),
+            access,
+            last_run: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecs::{RemovedComponents, ResMut, Schedule, World};
+
+    struct Unregistered;
+
+    struct NumRemoved(usize);
+
+    fn count_removed_system(
+        mut removed: RemovedComponents<Unregistered>,
+        mut num_removed: ResMut<NumRemoved>,
+    ) {
+        num_removed.0 += removed.read().count();
+    }
+
+    #[test]
+    fn removed_components_of_unregistered_type_are_empty() {
+        let mut world = World::new();
+        world.insert_resource(NumRemoved(0));
+        let mut schedule = Schedule::new();
+        schedule.add_stage("update");
+        schedule.add_system("update", "count_removed", count_removed_system);
+        schedule.run(&mut world);
+        assert_eq!(world.get_resource::<NumRemoved>().unwrap().0, 0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::{mem, panic::{self, AssertUnwindSafe}, sync::mpsc::{self, Receiver, Sender}, thread::{self, JoinHandle}};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A worker thread, which runs each job it is sent until its channel is closed.
+struct Worker {
+    sender: Sender<Job>,
+    handle: JoinHandle<()>,
+}
+
+/// Persistent worker threads for a World, so that running a Schedule stage (or a query) in parallel doesn't spawn new threads.
+/// Workers are only spawned once they are first needed, and live until the ThreadPool is dropped.
+pub(super) struct ThreadPool {
+    workers: Vec<Worker>,
+    /// Each worker sends the result of every job it runs here, so `run` can wait for them all to finish.
+    finished_sender: Sender<thread::Result<()>>,
+    finished_receiver: Receiver<thread::Result<()>>,
+}
+
+impl ThreadPool {
+    pub(super) fn new() -> ThreadPool {
+        let (finished_sender, finished_receiver) = mpsc::channel();
+        ThreadPool {
+            workers: Vec::new(),
+            finished_sender,
+            finished_receiver,
+        }
+    }
+
+    /// Spawn workers until there are at least `num_workers`.
+    pub(super) fn reserve_workers(
+        &mut self,
+        num_workers: usize,
+    ) {
+        while self.workers.len() < num_workers {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let finished_sender = self.finished_sender.clone();
+            let handle = thread::spawn(move | | {
+                for job in receiver {
+                    // A panicking job mustn't take the worker down with it, the panic is propagated by `run` instead
+                    let result = panic::catch_unwind(AssertUnwindSafe(job));
+                    if finished_sender.send(result).is_err() {
+                        return
+                    }
+                }
+            });
+            self.workers.push(Worker { sender, handle });
+        }
+    }
+
+    /// Run `job` once on each of the first `num_workers` workers, and `on_caller` on the calling thread,
+    /// returning once they have all finished. See `reserve_workers`.
+    /// If any of them panic, the panic is propagated to the caller once they have all finished.
+    pub(super) fn run(
+        &self,
+        num_workers: usize,
+        job: &(dyn Fn() + Sync),
+        on_caller: impl FnOnce(),
+    ) {
+        assert!(num_workers <= self.workers.len(), "ThreadPool doesn't have enough workers!");
+        // SAFETY: the job is only borrowed until every worker has finished running it,
+        // which `run` waits for before returning (or propagating a panic)
+        let job: &'static (dyn Fn() + Sync) = unsafe { mem::transmute(job) };
+        for worker in &self.workers[..num_workers] {
+            worker.sender
+                .send(Box::new(job))
+                .expect("ThreadPool worker stopped!");
+        }
+        let caller_result = panic::catch_unwind(AssertUnwindSafe(on_caller));
+        let mut worker_result = Ok(());
+        for _ in 0..num_workers {
+            let result = self.finished_receiver
+                .recv()
+                .expect("ThreadPool worker stopped!");
+            if worker_result.is_ok() {
+                worker_result = result;
+            }
+        }
+        if let Err(payload) = caller_result.and(worker_result) {
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Closing each worker's channel stops it once it has finished its current job
+        for worker in self.workers.drain(..) {
+            drop(worker.sender);
+            let _ = worker.handle.join();
+        }
+    }
+}
@@ -9,13 +9,15 @@ pub trait Vector {
 
     fn abs(&self) -> Self::Scalar;
     fn normalize(&self) -> Self;
+    #[allow(dead_code)]
     fn dot(&self, rhs: Self) -> Self::Scalar;
+    #[allow(dead_code)]
     fn angle_to(&self, rhs: Self) -> Self::Scalar;
 }
 
 pub mod f32 {
     #[repr(C)]
-    #[derive(Copy, Clone, Debug)]
+    #[derive(Copy, Clone, Debug, PartialEq)]
     pub struct Vec2 {
         pub x: f32,
         pub y: f32,
@@ -163,6 +165,7 @@ pub mod u32 {
     }
 }
 
+#[allow(dead_code)]
 pub mod u8 {
     use super::LinalgError;
 
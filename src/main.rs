@@ -8,9 +8,11 @@ mod bundle;
 use std::{collections::HashSet};
 
 use miniquad::*;
+use bundle::Bundle;
 use resources::ResourceManager;
+use component::{Input, Renderer, Time};
 use linalg::{f32, u32};
-use system::{apply_velocity_system, collision_cleanup_system, collision_detection_system, collision_resolution_system, enemy_movement_system, player_movement_system, render_system, shoot_gun_system};
+use system::{apply_velocity_system, collision_detection_system, collision_resolution_system, enemy_movement_system, event_update_system, player_movement_system, render_system, shoot_gun_system, time_system, transform_propagation_system};
 
 const MAX_SPRITES: usize = 1024;
 
@@ -21,13 +23,9 @@ struct Vertex {
 }
 
 struct Stage {
-    ctx: Box<dyn RenderingBackend>,
-    pipeline: Pipeline,
-    bindings: Bindings,
-    mouse_position: f32::Vec2,
-    pressed_keys: HashSet<KeyCode>,
     world: ecs::World,
-    texture_atlas_entity: ecs::Entity, // TODO: Update the resource manager so this isn't an entity anymore
+    update_schedule: ecs::Schedule,
+    draw_schedule: ecs::Schedule,
 }
 
 impl Stage {
@@ -151,113 +149,125 @@ impl Stage {
             }
         );
         
-        // Create HashSet for storing pressed keys
-        let pressed_keys = HashSet::new();
-        // Create Vec2 for storing mouse position
-        let mouse_position = f32::Vec2 { x: 0.0, y: 0.0 };
-
         // Set up level
         let mut world = ecs::World::new();
-        world.register_component::<component::Transform>();
-        world.register_component::<component::Velocity>();
-        world.register_component::<component::Sprite>();
-        world.register_component::<component::Player>();
+
+        // Store input state (HashSet of pressed keys, Vec2 of mouse position) and time
+        world.insert_resource(Input {
+            pressed_keys: HashSet::new(),
+            mouse_position: f32::Vec2 { x: 0.0, y: 0.0 },
+        });
+        world.insert_resource(Time {
+            now: date::now(),
+            delta: 0.0,
+        });
+
+        // Components that are iterated over together every frame are stored in tables
+        world.register_component_with_storage::<component::Transform>(ecs::StorageType::Table);
+        world.register_component_with_storage::<component::GlobalTransform>(ecs::StorageType::Table);
+        world.register_component_with_storage::<component::Velocity>(ecs::StorageType::Table);
+        world.register_component_with_storage::<component::Sprite>(ecs::StorageType::Table);
+        world.register_component_with_storage::<component::Collider>(ecs::StorageType::Table);
+        world.register_component_with_hint::<component::Player>(ecs::StorageHint::Rare);
         world.register_component::<component::Enemy>();
-        world.register_component::<component::Bullet>();
         world.register_component::<component::Wall>();
-        world.register_component::<component::TextureAtlas>();
-        world.register_component::<component::TileMap>();
-        world.register_component::<component::ChildOf>();
+        world.register_component_with_hint::<component::TileMap>(ecs::StorageHint::Rare);
         world.register_component::<component::ShootsBullet>();
-        world.register_component::<component::Collider>();
-        world.register_component::<component::CollisionEvent>();
+        // Registers whichever of the bullet's components weren't registered above
+        bundle::BulletBundle::register_components(&mut world);
+
+        world.register_event::<component::CollisionEvent>();
+
+        world.on_add::<component::Transform>(component::GlobalTransform::add_to);
+        world.on_add::<component::TileMap>(component::TileMap::spawn_colliders);
 
         // Create texture atlas
         // TODO: Explicitly link this to `texture`
-        let texture_atlas_entity = world.create_entity();
-        world.add_component(&texture_atlas_entity, component::TextureAtlas::new(texture_atlas_size, sprite_size)).unwrap();
+        world.insert_resource(component::TextureAtlas::new(texture_atlas_size, sprite_size));
 
         // Create tile map
-        let tile_map = world.create_entity();
-        world.add_component(&tile_map, component::Transform { position: f32::Vec2 { x: -1.0, y: -1.0 } }).unwrap();
         let tile_size = f32::Vec2 { x: 0.1, y: 0.1 };
-        let tile_map_component = component::TileMap::new(tiles, tile_size);
         let collidable_tile_ids: HashSet<u8> = [0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27]
             .into_iter()
             .collect();
-        tile_map_component.spawn_colliders(&mut world, &tile_map, tile_size, &collidable_tile_ids);
-        world.add_component(&tile_map, tile_map_component).unwrap();
+        world.spawn((
+            component::Transform { position: f32::Vec2 { x: -1.0, y: -1.0 } },
+            component::TileMap::new(tiles, tile_size, collidable_tile_ids),
+        )).unwrap();
 
         // Create player
-        let player = world.create_entity();
-        world.add_component(&player, component::Transform { position: f32::Vec2 { x: 0.5, y: 0.2 } }).unwrap();
-        world.add_component(&player, component::Sprite { atlas_texture_index: 28 }).unwrap();
-        world.add_component(&player, component::Player { } ).unwrap();
-        world.add_component(&player, component::Collider { size: f32::Vec2 { x: 0.1, y: 0.1 }, is_static: false } ).unwrap();
-
-        // Create gun to demonstrate ChildOf component
-        let gun = world.create_entity();
-        world.add_component(&gun, component::ChildOf { parent: player }).unwrap();
-        world.add_component(&gun, component::Transform { position: f32::Vec2 { x: 0.05, y: 0.0 } }).unwrap();
-        world.add_component(&gun, component::Sprite { atlas_texture_index: 29 }).unwrap();
-        world.add_component(&gun, component::ShootsBullet { bullet_speed: 0.01, is_active: true }).unwrap();
+        let player = world.spawn((
+            component::Transform { position: f32::Vec2 { x: 0.5, y: 0.2 } },
+            component::Sprite { atlas_texture_index: 28 },
+            component::Player { },
+            component::Collider { size: f32::Vec2 { x: 0.1, y: 0.1 }, is_static: false },
+        )).unwrap();
+
+        // Create gun to demonstrate the hierarchy
+        let gun = world.spawn((
+            component::Transform { position: f32::Vec2 { x: 0.05, y: 0.0 } },
+            component::Sprite { atlas_texture_index: 29 },
+            component::ShootsBullet { bullet_speed: 0.01, is_active: true },
+        )).unwrap();
+        world.set_parent(gun, player).unwrap();
 
         // Create enemy
-        let enemy = world.create_entity();
-        world.add_component(&enemy, component::Transform { position: f32::Vec2 { x: 0.5, y: 0.7 } }).unwrap();
-        world.add_component(&enemy, component::Sprite { atlas_texture_index: 36 }).unwrap();
-        world.add_component(&enemy, component::Enemy { } ).unwrap();
-
-        Stage {
+        world.spawn((
+            component::Transform { position: f32::Vec2 { x: 0.5, y: 0.7 } },
+            component::Sprite { atlas_texture_index: 36 },
+            component::Enemy { },
+        )).unwrap();
+
+        // Spawn the tile map's colliders and give everything a GlobalTransform (see the hooks above)
+        world.apply_hook_commands();
+
+        // Keep the resource manager around, so later systems can load resources too
+        world.insert_resource(resource_manager);
+        world.insert_non_send_resource(Renderer {
             ctx,
             pipeline,
             bindings,
-            pressed_keys,
-            mouse_position,
+            num_instances: 0,
+        });
+
+        // Set up systems
+        let mut update_schedule = ecs::Schedule::new();
+        update_schedule
+            .add_stage("input")
+            .add_stage("simulation")
+            .add_stage("physics");
+        update_schedule.add_system("input", "time", time_system);
+        update_schedule.add_system("input", "event_update", event_update_system);
+        update_schedule.add_system("simulation", "player_movement", player_movement_system);
+        update_schedule.add_system("simulation", "enemy_movement", enemy_movement_system);
+        update_schedule.add_system("simulation", "shoot_gun", shoot_gun_system);
+        update_schedule.add_system("physics", "apply_velocity", apply_velocity_system);
+        update_schedule.add_system("physics", "transform_propagation", transform_propagation_system)
+            .after("apply_velocity");
+        update_schedule.add_system("physics", "collision_detection", collision_detection_system)
+            .after("transform_propagation");
+        update_schedule.add_system("physics", "collision_resolution", collision_resolution_system)
+            .after("collision_detection");
+
+        let mut draw_schedule = ecs::Schedule::new();
+        draw_schedule.add_stage("render");
+        draw_schedule.add_system("render", "render", render_system);
+
+        Stage {
             world,
-            texture_atlas_entity,
+            update_schedule,
+            draw_schedule,
         }
     }
 }
 
 impl EventHandler for Stage {
     fn update(&mut self) {
-        player_movement_system(
-            &mut self.world,
-            &self.pressed_keys,
-        );
-        enemy_movement_system(
-            &mut self.world,
-        );
-        shoot_gun_system(
-            &mut self.world,
-            &self.mouse_position,
-            &self.pressed_keys,
-        );
-        apply_velocity_system(
-            &mut self.world,
-        );
-        collision_detection_system(
-            &mut self.world,
-        );
-        collision_resolution_system(
-            &mut self.world,
-        );
-        collision_cleanup_system(
-            &mut self.world,
-        );
+        self.update_schedule.run(&mut self.world);
     }
 
     fn draw(&mut self) {
-        render_system(
-            &self.world,
-            &mut self.ctx,
-            &self.bindings,
-            &self.pipeline,
-            &self.texture_atlas_entity,
-        );
-        self.ctx.end_render_pass();
-        self.ctx.commit_frame();
+        self.draw_schedule.run(&mut self.world);
     }
 
     fn key_down_event(
@@ -266,7 +276,7 @@ impl EventHandler for Stage {
         _keymods: KeyMods,
         _repeat: bool,
     ) {
-        self.pressed_keys.insert(_keycode);
+        self.world.get_resource_mut::<Input>().expect("Input resource missing from the world!").pressed_keys.insert(_keycode);
     }
 
     fn key_up_event(
@@ -274,7 +284,7 @@ impl EventHandler for Stage {
         _keycode: KeyCode,
         _keymods: KeyMods,
     ) {
-        self.pressed_keys.remove(&_keycode);
+        self.world.get_resource_mut::<Input>().expect("Input resource missing from the world!").pressed_keys.remove(&_keycode);
     }
 
     fn mouse_motion_event(
@@ -282,8 +292,9 @@ impl EventHandler for Stage {
         _x: f32,
         _y: f32,
     ) {
-        self.mouse_position.x = _x;
-        self.mouse_position.y = _y;
+        let mut input = self.world.get_resource_mut::<Input>().expect("Input resource missing from the world!");
+        input.mouse_position.x = _x;
+        input.mouse_position.y = _y;
     }
 }
 
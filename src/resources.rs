@@ -13,7 +13,7 @@ pub enum ResourceError {
     /// Failed to parse a resource.
     ParseError,
     /// Miniquad failed to load a file.
-    MiniquadFsError(miniquad::fs::Error),
+    MiniquadFsError(#[allow(dead_code)] miniquad::fs::Error),
     /// Tried to parse a resource before it was loaded.
     ResourceNotReady,
 }
@@ -57,6 +57,7 @@ impl ResourceManager {
         }
     }
 
+    #[allow(dead_code)]
     fn get_as_bytes_mut(
         &mut self,
         resource: &Resource,
@@ -1,16 +1,32 @@
 use std::collections::HashSet;
 
-use miniquad::{date, window, Bindings, BufferSource, KeyCode, Pipeline, RenderingBackend, UniformsSource};
+use miniquad::{date, window, Bindings, BufferSource, KeyCode, RenderingBackend, UniformsSource};
 
-use crate::{bundle::BulletBundle, component::{Bullet, ChildOf, Collider, CollisionEvent, Enemy, Player, ShootsBullet, Sprite, TextureAtlas, TileMap, Transform, Velocity}, ecs::{Entity, World}, linalg::{f32, Vector}, shader};
+use crate::{bundle::BulletBundle, component::{Bullet, Collider, CollisionEvent, Enemy, GlobalTransform, Input, Player, Renderer, ShootsBullet, Sprite, TextureAtlas, TileMap, Time, Transform, Velocity}, ecs::{Changed, Children, Commands, Entity, EventReader, EventWriter, NonSendMut, Parent, QueryParam, RemovedComponents, Res, ResMut, With, Without, World}, linalg::{f32, Vector}, shader};
+
+pub fn time_system(
+    mut time: ResMut<Time>,
+) {
+    let now = date::now();
+    time.delta = now - time.now;
+    time.now = now;
+}
+
+pub fn event_update_system(
+    world: &World,
+) {
+    world.update_events();
+    world.update_removed_components();
+}
 
 pub fn player_movement_system(
-    world: &mut World,
-    pressed_keys: &HashSet<KeyCode>,
+    input: Res<Input>,
+    players: QueryParam<(With<Player>, &mut Transform)>,
 ) {
     let mut movement_vec = f32::Vec2 { x: 0.0, y: 0.0 };
     let speed = 0.01;
 
+    let pressed_keys = &input.pressed_keys;
     if pressed_keys.contains(&KeyCode::W) {
         movement_vec.y += 1.0;
     };
@@ -27,7 +43,7 @@ pub fn player_movement_system(
     movement_vec = movement_vec.normalize();
     movement_vec *= speed;
 
-    for (_, (_player_control, mut transform)) in world.query_mut::<(&Player, &Transform)>() {
+    for (_, (_player_control, mut transform)) in &players {
         transform.position.x += movement_vec.x;
         transform.position.y += movement_vec.y;
     }
@@ -44,25 +60,22 @@ pub fn screen_to_world(
 }
 
 pub fn shoot_gun_system(
-    world: &mut World,
-    mouse_position: &f32::Vec2,
-    pressed_keys: &HashSet<KeyCode>,
+    input: Res<Input>,
+    guns: QueryParam<(&ShootsBullet, &GlobalTransform)>,
+    mut commands: Commands,
 ) {
     // TODO: Change to mouse click
-    if pressed_keys.contains(&KeyCode::Space) {
-        let shoot_data: Vec<(f32::Vec2, f32::Vec2)> = world.query::<(&ShootsBullet, &Transform)>()
-            .map(| (entity, (shoots_bullet, transform)) | {
-                let world_position = compute_world_position(world, &entity, &transform);
-                let velocity_vec = (screen_to_world(mouse_position) - world_position).normalize() * shoots_bullet.bullet_speed;
-                (velocity_vec, world_position)
-            })
-            .collect();
-
-        for (velocity_vec, position) in shoot_data {
-            let bullet = world.create_entity();
-            world.add_bundle(&bullet, BulletBundle {
+    if input.pressed_keys.contains(&KeyCode::Space) {
+        for (_, (shoots_bullet, global_transform)) in &guns {
+            let world_position = global_transform.position;
+            let velocity_vec = (screen_to_world(&input.mouse_position) - world_position).normalize() * shoots_bullet.bullet_speed;
+            let Ok(bullet) = commands.spawn() else {
+                println!("Ran out of Entities to spawn bullets with!");
+                return
+            };
+            commands.add_bundle(bullet, BulletBundle {
                 transform: Transform {
-                    position,
+                    position: world_position,
                 },
                 velocity: Velocity {
                     vec: velocity_vec,
@@ -74,10 +87,11 @@ pub fn shoot_gun_system(
 }
 
 pub fn enemy_movement_system(
-    world: &mut World,
+    time: Res<Time>,
+    enemies: QueryParam<(With<Enemy>, &mut Transform)>,
 ) {
-    let mut t = date::now() * 0.3;
-    for (entity, (_enemy_control, mut transform)) in world.query_mut::<(&Enemy, &Transform)>() {
+    let mut t = time.now * 0.3;
+    for (entity, (_enemy_control, mut transform)) in &enemies {
         t += entity.get_id() as f64;
         transform.position.x = t.sin() as f32 * 0.5;
         transform.position.y = (t * 3.).cos() as f32 * 0.5;
@@ -85,50 +99,58 @@ pub fn enemy_movement_system(
 }
 
 pub fn apply_velocity_system(
-    world: &mut World,
+    moving: QueryParam<(&Velocity, &mut Transform)>,
 ) {
-    for (_, (velocity, mut transform)) in world.query_mut::<(&Velocity, &Transform)>() {
+    moving.par_for_each(| _, (velocity, mut transform) | {
         transform.position += velocity.vec;
-    }
+    });
 }
 
-fn compute_world_position(
-    world: &World,
-    entity: &Entity,
-    transform: &Transform,
-) -> f32::Vec2 {
-    if let Some(child_of) = world.get_component::<ChildOf>(entity).unwrap() {
-        let parent_transform = world.get_component::<Transform>(&child_of.parent).unwrap().expect("Parent referenced in ChildOf component did not have a Transform component!");
-        transform.position + parent_transform.position
-    } else {
-        transform.position
+/// Update the GlobalTransform of every Entity, walking down the hierarchy from each root.
+/// Descendants of an Entity without a Transform keep their old GlobalTransforms.
+/// World::set_parent never creates cycles, but if there were one it would be unreachable from a root,
+/// so this always terminates.
+pub fn transform_propagation_system(
+    roots: QueryParam<(With<GlobalTransform>, Without<Parent>)>,
+    transforms: QueryParam<(&Transform, &mut GlobalTransform, Option<&Children>)>,
+) {
+    let mut stack: Vec<(Entity, f32::Vec2)> = roots
+        .iter()
+        .map(| (entity, _) | (entity, f32::Vec2 { x: 0.0, y: 0.0 }))
+        .collect();
+    while let Some((entity, parent_position)) = stack.pop() {
+        let Some((transform, mut global_transform, children)) = transforms.get(&entity) else {
+            continue
+        };
+        let position = transform.position + parent_position;
+        // Only write if it has moved, so unmoved GlobalTransforms aren't detected as changed
+        if global_transform.position != position {
+            global_transform.position = position;
+        }
+        if let Some(children) = children {
+            stack.extend(children.iter().map(| child | (*child, position)));
+        }
     }
 }
 
-pub fn render_system(
-    world: &World,
-    ctx: &mut Box<dyn RenderingBackend>,
+/// Fill the instance buffers with the position and texture of every tile and sprite,
+/// returning the number of instances.
+fn update_instance_buffers(
+    ctx: &mut dyn RenderingBackend,
     bindings: &Bindings,
-    pipeline: &Pipeline,
-    texture_atlas_entity: &Entity,
-) {
-    let screen_size = {
-        let (x, y) = window::screen_size();
-        f32::Vec2 { x, y }
-    };
-
+    texture_atlas: &TextureAtlas,
+    tile_maps: &QueryParam<(&GlobalTransform, &TileMap)>,
+    sprites: &QueryParam<(&GlobalTransform, &Sprite)>,
+) -> usize {
     let mut positions = Vec::new();
     let mut uv_offsets: Vec<f32::Vec2> = Vec::new();
-    let texture_atlas = world.get_component::<TextureAtlas>(texture_atlas_entity)
-        .unwrap()
-        .expect("Texture atlas entity missing a texture atlas component!");
 
-    world.query::<(&Transform, &TileMap)>()
-        .for_each(| (_, (transform, tile_map)) | {
+    tile_maps.iter()
+        .for_each(| (_, (global_transform, tile_map)) | {
             positions.extend(tile_map.tile_positions
                 .iter()
                 .map(| position | {
-                    *position + transform.position
+                    *position + global_transform.position
                 })
             );
             uv_offsets.extend(
@@ -143,9 +165,9 @@ pub fn render_system(
         });
 
     
-    world.query::<(&Transform, &Sprite)>()
-        .for_each(| (entity, (transform, sprite)) | {
-            positions.push(compute_world_position(world, &entity, &transform));
+    sprites.iter()
+        .for_each(| (_, (global_transform, sprite)) | {
+            positions.push(global_transform.position);
             // TODO: Parameterise default texture
             uv_offsets.push(
                 texture_atlas.uv_offsets
@@ -164,6 +186,36 @@ pub fn render_system(
         bindings.vertex_buffers[2],
         BufferSource::slice(&positions[..]),
     );
+    positions.len()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn render_system(
+    mut renderer: NonSendMut<Renderer>,
+    texture_atlas: Res<TextureAtlas>,
+    tile_maps: QueryParam<(&GlobalTransform, &TileMap)>,
+    sprites: QueryParam<(&GlobalTransform, &Sprite)>,
+    changed_transforms: QueryParam<Changed<GlobalTransform>>,
+    changed_sprites: QueryParam<Changed<Sprite>>,
+    changed_tile_maps: QueryParam<Changed<TileMap>>,
+    mut removed_sprites: RemovedComponents<Sprite>,
+    mut removed_tile_maps: RemovedComponents<TileMap>,
+) {
+    let Renderer { ctx, pipeline, bindings, num_instances } = &mut *renderer;
+    let screen_size = {
+        let (x, y) = window::screen_size();
+        f32::Vec2 { x, y }
+    };
+
+    // Only rebuild the instance buffers if something drawn has been added, changed or removed since the last frame.
+    // Read every removal (rather than stopping at the first) so they aren't seen again next frame
+    let is_removed = removed_sprites.read().count() + removed_tile_maps.read().count() > 0;
+    let is_changed = changed_transforms.iter().next().is_some() ||
+        changed_sprites.iter().next().is_some() ||
+        changed_tile_maps.iter().next().is_some();
+    if is_removed || is_changed {
+        *num_instances = update_instance_buffers(ctx.as_mut(), bindings, &texture_atlas, &tile_maps, &sprites);
+    }
 
     ctx.begin_default_pass(Default::default());
 
@@ -186,20 +238,19 @@ pub fn render_system(
         offset: (0.0, 0.0),
     }));
 
-    ctx.draw(0, 6, positions.len() as i32);
+    ctx.draw(0, 6, *num_instances as i32);
+    ctx.end_render_pass();
+    ctx.commit_frame();
 }
 
 fn colliders_colliding(
-    world: &World,
-    entity_a: &Entity,
     collider_a: &Collider,
-    transform_a: &Transform,
-    entity_b: &Entity,
+    global_transform_a: &GlobalTransform,
     collider_b: &Collider,
-    transform_b: &Transform,
+    global_transform_b: &GlobalTransform,
 ) -> bool {
-    let position_a = compute_world_position(world, entity_a, transform_a);
-    let position_b = compute_world_position(world, entity_b, transform_b);
+    let position_a = global_transform_a.position;
+    let position_b = global_transform_b.position;
     {
         position_a.x < position_b.x + collider_b.size.x &&
         position_a.x + collider_a.size.x > position_b.x &&
@@ -209,15 +260,16 @@ fn colliders_colliding(
 }
 
 pub fn collision_detection_system(
-    world: &mut World,
+    mut collision_events: EventWriter<CollisionEvent>,
+    colliders: QueryParam<(&Collider, &GlobalTransform)>,
 ) {
     // TODO: Implement quadtree
-    for (entity_a, (collider_a, transform_a)) in world.query::<(&Collider, &Transform)>() {
-        for (entity_b, (collider_b, transform_b)) in world.query::<(&Collider, &Transform)>() {
+    for (entity_a, (collider_a, global_transform_a)) in &colliders {
+        for (entity_b, (collider_b, global_transform_b)) in &colliders {
             if entity_a == entity_b { continue }
-            if colliders_colliding(world, &entity_a, &collider_a, &transform_a, &entity_b, &collider_b, &transform_b) && !collider_a.is_static {
-                world.add_component(&entity_a, CollisionEvent { entity_a, entity_b }).unwrap();
-                // We'll add the CollisionEvent component to `entity_b` on the second pass
+            if colliders_colliding(&collider_a, &global_transform_a, &collider_b, &global_transform_b) && !collider_a.is_static {
+                collision_events.send(CollisionEvent { entity_a, entity_b });
+                // We'll send the CollisionEvent for `entity_b` on the second pass
                 // FIXME: Iterating through all the entities twice is so inefficient!
             }
         }
@@ -225,33 +277,21 @@ pub fn collision_detection_system(
 }
 
 pub fn collision_resolution_system(
-    world: &mut World,
+    mut collision_events: EventReader<CollisionEvent>,
+    bullets: QueryParam<With<Bullet>>,
+    players: QueryParam<With<Player>>,
+    mut commands: Commands,
 ) {
-    let mut bullet_entities = Vec::new();
-    for (entity, collision_event, ) in world.query::<&CollisionEvent>() {
-        if world.get_component::<Bullet>(&collision_event.entity_a).unwrap().is_some() &&
-        world.get_component::<Bullet>(&collision_event.entity_b).unwrap().is_none() && 
+    // A bullet may collide with several things at once, but should only be despawned once
+    let mut despawned_bullets = HashSet::new();
+    for collision_event in collision_events.read() {
+        // The colliding entities may have been despawned since the event was sent
+        if bullets.get(&collision_event.entity_a).is_some() &&
+        bullets.get(&collision_event.entity_b).is_none() &&
         // For now, bullets won't collide with the player
-        world.get_component::<Player>(&collision_event.entity_b).unwrap().is_none()
-        {
-            bullet_entities.push(entity);
+        players.get(&collision_event.entity_b).is_none() &&
+        despawned_bullets.insert(collision_event.entity_a) {
+            commands.despawn(collision_event.entity_a);
         }
     }
-    for entity in bullet_entities {
-        world.destroy_entity(entity);
-    }
 }
-
-pub fn collision_cleanup_system(
-    world: &mut World,
-) {
-    let entities: Vec<Entity> = world.query::<&CollisionEvent>()
-        .map(| (entity, _) | {
-            entity
-        })
-        .collect();
-    if entities.is_empty() { return }
-    for entity in entities {
-        world.remove_component::<CollisionEvent>(&entity).unwrap();
-    }
-}
\ No newline at end of file